@@ -0,0 +1,761 @@
+use crate::i8080::*;
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// Instructions executed between two checks for a Ctrl-C from the debugger
+const POLL_INSTRUCTIONS: usize = 10_000;
+
+/// Instructions that reverse-step and reverse-continue can take back
+const HISTORY_LENGTH: usize = 100_000;
+
+/// Byte GDB sends outside of a packet to interrupt the target
+const INTERRUPT: u8 = 0x03;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+/// The 8080 registers in the order of the `g` packet, each a little endian pair.
+/// GDB has no 8080 target, so the architecture names its closest relative.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>z80</architecture>
+  <feature name="org.i8080.cpu">
+    <flags id="i8080_psw" size="2">
+      <field name="CY" start="0" end="0"/>
+      <field name="P" start="2" end="2"/>
+      <field name="AC" start="4" end="4"/>
+      <field name="Z" start="6" end="6"/>
+      <field name="S" start="7" end="7"/>
+      <field name="A" start="8" end="15" type="uint8"/>
+    </flags>
+    <reg name="psw" bitsize="16" type="i8080_psw" regnum="0"/>
+    <reg name="bc" bitsize="16" type="int"/>
+    <reg name="de" bitsize="16" type="data_ptr"/>
+    <reg name="hl" bitsize="16" type="data_ptr"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+const REGISTER_COUNT: usize = 6;
+
+/// What the session does after a packet was handled
+enum Reply {
+    Packet(String),
+    /// Sends the packet and ends the session
+    Detach(String),
+    /// Ends the session without an answer
+    Kill,
+}
+
+/// Serves the GDB remote serial protocol for one debugger connection at a time
+pub struct GdbServer<'a> {
+    executor: Executor<'a>,
+}
+
+impl<'a> GdbServer<'a> {
+    pub fn new(mut executor: Executor<'a>) -> Self {
+        executor.enable_history(HISTORY_LENGTH);
+        Self { executor }
+    }
+
+    /// Waits for a debugger on `127.0.0.1:port` and serves it until it detaches or kills the target
+    pub fn listen(&mut self, port: u16) -> io::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        let (stream, _) = listener.accept()?;
+        self.serve(stream)
+    }
+
+    pub fn serve(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        loop {
+            let byte = match read_byte(&mut stream)? {
+                Some(byte) => byte,
+                None => return Ok(()),
+            };
+
+            let reply = match byte {
+                b'$' => match read_packet(&mut stream)? {
+                    Some(packet) => {
+                        stream.write_all(b"+")?;
+                        self.handle(&packet, &mut stream)?
+                    }
+                    None => {
+                        // Bad checksum, GDB sends the packet again
+                        stream.write_all(b"-")?;
+                        continue;
+                    }
+                },
+                INTERRUPT => Reply::Packet(stop_reply(SIGINT)),
+                // Acknowledgements of our own packets
+                _ => continue,
+            };
+
+            match reply {
+                Reply::Packet(packet) => write_packet(&mut stream, &packet)?,
+                Reply::Detach(packet) => return write_packet(&mut stream, &packet),
+                Reply::Kill => return Ok(()),
+            }
+        }
+    }
+
+    fn handle(&mut self, packet: &[u8], stream: &mut TcpStream) -> io::Result<Reply> {
+        let packet = String::from_utf8_lossy(packet);
+        let command = match packet.chars().next() {
+            Some(command) => command,
+            None => return Ok(Reply::Packet(String::new())),
+        };
+        let args = &packet[command.len_utf8()..];
+
+        let reply = match command {
+            '?' => stop_reply(SIGTRAP),
+            'g' => self.read_registers(),
+            'G' => ok_or_error(self.write_registers(args)),
+            'p' => self.read_register(args).unwrap_or_else(error),
+            'P' => ok_or_error(self.write_register(args)),
+            'm' => self.read_memory(args).unwrap_or_else(error),
+            'M' => ok_or_error(self.write_memory(args)),
+            's' => match self.resume_at(args) {
+                Some(()) => self.step(),
+                None => error(),
+            },
+            'c' => match self.resume_at(args) {
+                Some(()) => self.resume(stream)?,
+                None => error(),
+            },
+            'Z' => ok_or_unsupported(self.breakpoint(args, true)),
+            'z' => ok_or_unsupported(self.breakpoint(args, false)),
+            'b' => match args {
+                "s" => self.reverse_step(),
+                "c" => {
+                    let reason = self.executor.reverse_continue();
+                    self.reason_reply(reason)
+                }
+                _ => String::new(),
+            },
+            'q' => query(args),
+            'H' => String::from("OK"),
+            'D' => return Ok(Reply::Detach(String::from("OK"))),
+            'k' => return Ok(Reply::Kill),
+            // An empty reply tells GDB that the packet is not supported
+            _ => String::new(),
+        };
+        Ok(Reply::Packet(reply))
+    }
+
+    fn registers(&self) -> [u16; REGISTER_COUNT] {
+        let cpu = self.executor.cpu();
+        [
+            cpu.get_psw(),
+            cpu.get_bc(),
+            cpu.get_de(),
+            cpu.get_hl(),
+            cpu.sp,
+            cpu.pc,
+        ]
+    }
+
+    fn set_register(&mut self, index: usize, value: u16) -> Option<()> {
+        let cpu = self.executor.cpu_mut();
+        match index {
+            0 => cpu.set_psw(value),
+            1 => cpu.set_bc(value),
+            2 => cpu.set_de(value),
+            3 => cpu.set_hl(value),
+            4 => cpu.sp = value,
+            5 => cpu.pc = value,
+            _ => return None,
+        }
+        // Undo records would put back the values from before the edit
+        self.executor.clear_history();
+        Some(())
+    }
+
+    fn read_registers(&self) -> String {
+        let bytes: Vec<u8> = self
+            .registers()
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        encode_hex(&bytes)
+    }
+
+    fn write_registers(&mut self, args: &str) -> Option<()> {
+        let bytes = decode_hex(args)?;
+        if bytes.len() != REGISTER_COUNT * 2 {
+            return None;
+        }
+        for (index, value) in bytes.chunks(2).enumerate() {
+            self.set_register(index, u16::from_le_bytes([value[0], value[1]]))?;
+        }
+        Some(())
+    }
+
+    fn read_register(&self, args: &str) -> Option<String> {
+        let index = usize::from_str_radix(args, 16).ok()?;
+        let value = self.registers().get(index).copied()?;
+        Some(encode_hex(&value.to_le_bytes()))
+    }
+
+    fn write_register(&mut self, args: &str) -> Option<()> {
+        let (index, value) = args.split_once('=')?;
+        let index = usize::from_str_radix(index, 16).ok()?;
+        let bytes = decode_hex(value)?;
+        if bytes.len() != 2 {
+            return None;
+        }
+        self.set_register(index, u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (address, length) = parse_range(args)?;
+        let bus = &self.executor.cpu().bus;
+        let bytes: Vec<u8> = (0..length)
+            .map(|offset| bus.peek_byte(address.wrapping_add(offset as u16)))
+            .collect();
+        Some(encode_hex(&bytes))
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<()> {
+        let (range, data) = args.split_once(':')?;
+        let (address, length) = parse_range(range)?;
+        let bytes = decode_hex(data)?;
+        if bytes.len() != length {
+            return None;
+        }
+        self.executor
+            .cpu_mut()
+            .bus
+            .load_bytes(address, &bytes)
+            .ok()?;
+        self.executor.clear_history();
+        Some(())
+    }
+
+    /// `s` and `c` optionally name the address to resume at
+    fn resume_at(&mut self, args: &str) -> Option<()> {
+        if !args.is_empty() {
+            self.executor.cpu_mut().pc = u16::from_str_radix(args, 16).ok()?;
+            self.executor.clear_history();
+        }
+        Some(())
+    }
+
+    fn reverse_step(&mut self) -> String {
+        if self.executor.reverse_step() {
+            stop_reply(SIGTRAP)
+        } else {
+            self.reason_reply(StopReason::StartOfHistory)
+        }
+    }
+
+    fn step(&mut self) -> String {
+        let reason = self.executor.run_instructions(1);
+        self.reason_reply(reason)
+    }
+
+    /// Runs until a breakpoint, watchpoint, HLT or an interrupt from the debugger
+    fn resume(&mut self, stream: &mut TcpStream) -> io::Result<String> {
+        loop {
+            let reason = self.executor.run_instructions(POLL_INSTRUCTIONS);
+            if reason != StopReason::BudgetExhausted {
+                return Ok(self.reason_reply(reason));
+            }
+            if poll_interrupt(stream)? {
+                return Ok(stop_reply(SIGINT));
+            }
+        }
+    }
+
+    /// Handles `Z` and `z` packets for software breakpoints and write, read and access watchpoints
+    fn breakpoint(&mut self, args: &str, insert: bool) -> Option<()> {
+        let mut fields = args.split(',');
+        let kind = fields.next()?;
+        let address = u16::from_str_radix(fields.next()?, 16).ok()?;
+        let length = u16::from_str_radix(fields.next()?, 16).ok()?;
+
+        let access = match kind {
+            "0" => {
+                let breakpoints = self.executor.breakpoints_mut();
+                if insert {
+                    breakpoints.add(address);
+                } else {
+                    breakpoints.remove(address);
+                }
+                return Some(());
+            }
+            "2" => Access::Write,
+            "3" => Access::Read,
+            "4" => Access::ReadWrite,
+            _ => return None,
+        };
+
+        let end = address.checked_add(length.checked_sub(1)?)?;
+        let watchpoint = Watchpoint::new(address..=end, access);
+        let watchpoints = self.executor.cpu_mut().bus.watchpoints();
+        if insert {
+            watchpoints.add(watchpoint);
+        } else {
+            watchpoints.remove(&watchpoint);
+        }
+        Some(())
+    }
+
+    fn reason_reply(&mut self, reason: StopReason) -> String {
+        match reason {
+            StopReason::Watchpoint(hit) => {
+                // The hit is a read or a write, GDB wants the kind of watchpoint that caught it
+                let access = self
+                    .executor
+                    .cpu_mut()
+                    .bus
+                    .watchpoints()
+                    .watchpoints()
+                    .iter()
+                    .find(|watchpoint| watchpoint.matches(hit.address, hit.access))
+                    .map_or(hit.access, |watchpoint| watchpoint.access);
+                let kind = match access {
+                    Access::Read => "rwatch",
+                    Access::Write => "watch",
+                    Access::ReadWrite => "awatch",
+                };
+                // GDB looks up the watchpoint by the data address that was accessed
+                format!("T{:02x}{}:{:x};", SIGTRAP, kind, hit.address)
+            }
+            // Tells GDB that there is nothing further back to replay
+            StopReason::StartOfHistory => format!("T{:02x}replaylog:begin;", SIGTRAP),
+            StopReason::Error(_) => stop_reply(SIGILL),
+            _ => stop_reply(SIGTRAP),
+        }
+    }
+}
+
+fn query(args: &str) -> String {
+    if args.starts_with("Supported") {
+        String::from("PacketSize=1000;qXfer:features:read+;ReverseStep+;ReverseContinue+")
+    } else if let Some(annex) = args.strip_prefix("Xfer:features:read:target.xml:") {
+        read_target_xml(annex).unwrap_or_else(error)
+    } else if args == "Attached" {
+        String::from("1")
+    } else {
+        String::new()
+    }
+}
+
+/// Answers a `qXfer` read of `offset,length` with the matching part of the target description
+fn read_target_xml(annex: &str) -> Option<String> {
+    let (offset, length) = annex.split_once(',')?;
+    let offset = usize::from_str_radix(offset, 16).ok()?;
+    let length = usize::from_str_radix(length, 16).ok()?;
+
+    let xml = TARGET_XML.as_bytes();
+    let start = offset.min(xml.len());
+    let end = offset.saturating_add(length).min(xml.len());
+    // `l` marks the last chunk, `m` asks GDB to read on
+    let marker = if end == xml.len() { 'l' } else { 'm' };
+    Some(format!(
+        "{}{}",
+        marker,
+        String::from_utf8_lossy(&xml[start..end])
+    ))
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+fn error() -> String {
+    String::from("E01")
+}
+
+fn ok_or_error(result: Option<()>) -> String {
+    result.map_or_else(error, |_| String::from("OK"))
+}
+
+fn ok_or_unsupported(result: Option<()>) -> String {
+    result.map_or_else(String::new, |_| String::from("OK"))
+}
+
+/// Parses the `address,length` of memory packets
+fn parse_range(args: &str) -> Option<(u16, usize)> {
+    let (address, length) = args.split_once(',')?;
+    let address = u16::from_str_radix(address, 16).ok()?;
+    let length = usize::from_str_radix(length, 16).ok()?;
+    if length > MEMORY_SIZE {
+        return None;
+    }
+    Some((address, length))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() & 0x1 != 0 || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn read_byte(stream: &mut TcpStream) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match stream.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+/// Reads the rest of a packet after the `$`. Returns `None` if the checksum does not match.
+fn read_packet(stream: &mut TcpStream) -> io::Result<Option<Vec<u8>>> {
+    let mut raw = Vec::new();
+    loop {
+        match read_byte(stream)? {
+            Some(b'#') => break,
+            Some(byte) => raw.push(byte),
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    let mut digits = [0; 2];
+    stream.read_exact(&mut digits)?;
+    let expected = std::str::from_utf8(&digits)
+        .ok()
+        .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+    if expected != Some(checksum(&raw)) {
+        return Ok(None);
+    }
+
+    // The checksum covers the escaped data, `}` escapes the following byte xor 20h
+    let mut packet = Vec::new();
+    let mut bytes = raw.into_iter();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'}' => packet.extend(bytes.next().map(|escaped| escaped ^ 0x20)),
+            _ => packet.push(byte),
+        }
+    }
+    Ok(Some(packet))
+}
+
+fn write_packet(stream: &mut TcpStream, packet: &str) -> io::Result<()> {
+    let mut data = Vec::new();
+    for byte in packet.bytes() {
+        if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+            data.push(b'}');
+            data.push(byte ^ 0x20);
+        } else {
+            data.push(byte);
+        }
+    }
+    write!(stream, "$")?;
+    stream.write_all(&data)?;
+    write!(stream, "#{:02x}", checksum(&data))
+}
+
+/// Checks without blocking whether the debugger sent a Ctrl-C
+fn poll_interrupt(stream: &mut TcpStream) -> io::Result<bool> {
+    stream.set_nonblocking(true)?;
+    let mut byte = [0];
+    let result = stream.read(&mut byte);
+    stream.set_nonblocking(false)?;
+
+    match result {
+        Ok(1) => Ok(byte[0] == INTERRUPT),
+        Ok(_) => Ok(false),
+        Err(error) if error.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+    use std::time::Duration;
+
+    /// Sends a packet the way GDB does and returns the answer
+    fn exchange(stream: &mut TcpStream, packet: &str) -> String {
+        write_packet(stream, packet).unwrap();
+        assert_eq!(read_byte(stream).unwrap(), Some(b'+'), "ack of {}", packet);
+        assert_eq!(
+            read_byte(stream).unwrap(),
+            Some(b'$'),
+            "answer to {}",
+            packet
+        );
+        let answer = read_packet(stream).unwrap().expect("valid checksum");
+        stream.write_all(b"+").unwrap();
+        String::from_utf8(answer).unwrap()
+    }
+
+    /// Serves `program` to a client thread that sends `packets` followed by a `k`
+    /// and returns the answers along with the machine after the session
+    fn session(program: &[u8], packets: &'static [&'static str]) -> (Vec<String>, CPU) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            let answers: Vec<String> = packets
+                .iter()
+                .map(|packet| exchange(&mut stream, packet))
+                .collect();
+            write_packet(&mut stream, "k").unwrap();
+            answers
+        });
+
+        let mut cpu = CPU::new();
+        cpu.bus.load_bytes(0, program).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        GdbServer::new(Executor::new(&mut cpu))
+            .serve(stream)
+            .unwrap();
+        (client.join().unwrap(), cpu)
+    }
+
+    /// MVI A,11h; STA 0040h; INR A; HLT
+    const PROGRAM: [u8; 7] = [0x3E, 0x11, 0x32, 0x40, 0x00, 0x3C, 0x76];
+
+    #[test]
+    fn reports_the_stop_signal() {
+        let (answers, _) = session(&PROGRAM, &["?"]);
+        assert_eq!(answers, ["S05"]);
+    }
+
+    #[test]
+    fn reads_and_writes_registers() {
+        let (answers, cpu) = session(
+            &PROGRAM,
+            &[
+                "g",
+                "G0200341278569abc00010000",
+                "g",
+                "P5=0500",
+                "p5",
+                "G0200",
+            ],
+        );
+        assert_eq!(
+            answers,
+            [
+                "020000000000000000000000",
+                "OK",
+                "0200341278569abc00010000",
+                "OK",
+                "0500",
+                "E01",
+            ]
+        );
+        assert_eq!(
+            (cpu.get_bc(), cpu.get_de(), cpu.get_hl()),
+            (0x1234, 0x5678, 0xBC9A)
+        );
+        assert_eq!((cpu.sp, cpu.pc), (0x0100, 0x0005));
+    }
+
+    #[test]
+    fn reads_and_writes_memory() {
+        let (answers, cpu) = session(&PROGRAM, &["m0,3", "M40,2:abcd", "m40,2", "M40,2:ab"]);
+        assert_eq!(answers, ["3e1132", "OK", "abcd", "E01"]);
+        assert_eq!(cpu.bus.peek_byte(0x0040), 0xAB);
+        assert_eq!(cpu.bus.peek_byte(0x0041), 0xCD);
+    }
+
+    #[test]
+    fn steps_and_continues_to_breakpoints() {
+        let (answers, cpu) = session(
+            &PROGRAM,
+            &["s", "p5", "Z0,5,1", "c", "p5", "z0,5,1", "c", "m40,1"],
+        );
+        assert_eq!(
+            answers,
+            ["S05", "0200", "OK", "S05", "0500", "OK", "S05", "11"]
+        );
+        assert_eq!(cpu.a, 0x12);
+        assert!(cpu.halted);
+    }
+
+    #[test]
+    fn reverse_step_takes_back_a_step() {
+        let (answers, cpu) = session(&PROGRAM, &["s", "s", "bs", "m40,1", "p5"]);
+        assert_eq!(answers, ["S05", "S05", "S05", "00", "0200"]);
+        assert_eq!(cpu.a, 0x11);
+    }
+
+    #[test]
+    fn host_edits_clear_the_history() {
+        let start_of_history = "T05replaylog:begin;";
+        let (answers, cpu) = session(
+            &PROGRAM,
+            &[
+                "s",
+                "s",
+                "M40,1:22",
+                "bs",
+                "s",
+                "P1=1234",
+                "bs",
+                "s",
+                "G020000000000000000010500",
+                "bs",
+            ],
+        );
+        assert_eq!(
+            answers,
+            [
+                "S05",
+                "S05",
+                "OK",
+                start_of_history,
+                "S05",
+                "OK",
+                start_of_history,
+                "S05",
+                "OK",
+                start_of_history,
+            ]
+        );
+        assert_eq!(cpu.bus.peek_byte(0x0040), 0x22);
+        assert_eq!(cpu.pc, 0x0005);
+    }
+
+    #[test]
+    fn step_resumes_at_the_given_address() {
+        let (answers, cpu) = session(&PROGRAM, &["s5", "p5"]);
+        assert_eq!(answers, ["S05", "0600"]);
+        assert_eq!(cpu.a, 0x01);
+    }
+
+    /// Value of `name="..."` in an XML element
+    fn attribute<'a>(element: &'a str, name: &str) -> &'a str {
+        let start = element.find(&format!("{}=\"", name)).unwrap() + name.len() + 2;
+        let length = element[start..].find('"').unwrap();
+        &element[start..start + length]
+    }
+
+    #[test]
+    fn register_packets_follow_the_target_description() {
+        // LXI B,1234h; LXI D,5678h; LXI H,9ABCh; LXI SP,0DEF0h; MVI A,80h; ORA A; HLT
+        let program = [
+            0x01, 0x34, 0x12, 0x11, 0x78, 0x56, 0x21, 0xBC, 0x9A, 0x31, 0xF0, 0xDE, 0x3E, 0x80,
+            0xB7, 0x76,
+        ];
+        let (answers, cpu) = session(
+            &program,
+            &[
+                "c",
+                "qXfer:features:read:target.xml:0,fff",
+                "g",
+                "p0",
+                "p1",
+                "p2",
+                "p3",
+                "p4",
+                "p5",
+            ],
+        );
+        let xml = answers[1].strip_prefix('l').unwrap();
+        let elements: Vec<&str> = xml.split('<').collect();
+
+        let registers: Vec<(&str, &str)> = elements
+            .iter()
+            .filter(|element| element.starts_with("reg "))
+            .map(|element| (attribute(element, "name"), attribute(element, "bitsize")))
+            .collect();
+        assert_eq!(
+            registers,
+            [
+                ("psw", "16"),
+                ("bc", "16"),
+                ("de", "16"),
+                ("hl", "16"),
+                ("sp", "16"),
+                ("pc", "16"),
+            ]
+        );
+
+        let values = [
+            cpu.get_psw(),
+            cpu.get_bc(),
+            cpu.get_de(),
+            cpu.get_hl(),
+            cpu.sp,
+            cpu.pc,
+        ];
+        assert_eq!(values, [0x8082, 0x1234, 0x5678, 0x9ABC, 0xDEF0, 0x0010]);
+        let g = &answers[2];
+        assert_eq!(g.len(), registers.len() * 4);
+        for (index, value) in values.iter().enumerate() {
+            let expected = encode_hex(&value.to_le_bytes());
+            assert_eq!(
+                &g[index * 4..index * 4 + 4],
+                expected,
+                "{}",
+                registers[index].0
+            );
+            assert_eq!(answers[3 + index], expected, "{}", registers[index].0);
+        }
+
+        let flags: Vec<(&str, usize)> = elements
+            .iter()
+            .filter(|element| element.starts_with("field "))
+            .map(|element| {
+                (
+                    attribute(element, "name"),
+                    attribute(element, "start").parse().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            flags,
+            [
+                ("CY", CARRY_FLAG),
+                ("P", PARITY_FLAG),
+                ("AC", AUX_CARRY_FLAG),
+                ("Z", ZERO_FLAG),
+                ("S", SIGN_FLAG),
+                ("A", 8),
+            ]
+        );
+    }
+
+    #[test]
+    fn watchpoint_stops_name_the_kind_of_watchpoint() {
+        // LDA 0040h; STA 0041h; LDA 0042h; STA 0042h; HLT
+        let program = [
+            0x3A, 0x40, 0x00, 0x32, 0x41, 0x00, 0x3A, 0x42, 0x00, 0x32, 0x42, 0x00, 0x76,
+        ];
+        let (answers, _) = session(
+            &program,
+            &["Z3,40,1", "Z2,41,1", "Z4,42,1", "c", "c", "c", "c", "c"],
+        );
+        assert_eq!(
+            answers,
+            [
+                "OK",
+                "OK",
+                "OK",
+                "T05rwatch:40;",
+                "T05watch:41;",
+                "T05awatch:42;",
+                "T05awatch:42;",
+                "S05",
+            ]
+        );
+    }
+}
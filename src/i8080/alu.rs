@@ -0,0 +1,306 @@
+use super::cpu::*;
+use super::util::*;
+
+// Flag computation follows the Intel 8080 Microcomputer Systems User's Manual.
+// Every function takes the current flags byte and updates it in place.
+
+pub fn add(flags: &mut u8, a: u8, value: u8, carry: bool) -> u8 {
+    let carry = carry as u16;
+    let result = a as u16 + value as u16 + carry;
+    let aux = (a & 0xF) as u16 + (value & 0xF) as u16 + carry > 0xF;
+
+    set_bit_enabled(flags, CARRY_FLAG, result > 0xFF);
+    set_bit_enabled(flags, AUX_CARRY_FLAG, aux);
+    set_zsp(flags, result as u8);
+    result as u8
+}
+
+pub fn sub(flags: &mut u8, a: u8, value: u8, borrow: bool) -> u8 {
+    // The 8080 subtracts by adding the two's complement, so the auxiliary carry
+    // is the carry out of bit 3 of that addition and the carry flag is inverted.
+    let result = add(flags, a, !value, !borrow);
+    let carry = get_bit(*flags, CARRY_FLAG);
+    set_bit_enabled(flags, CARRY_FLAG, !carry);
+    result
+}
+
+pub fn and(flags: &mut u8, a: u8, value: u8) -> u8 {
+    let result = a & value;
+    clear_bit(flags, CARRY_FLAG);
+    // ANA sets the auxiliary carry to the logical or of bit 3 of both operands
+    set_bit_enabled(flags, AUX_CARRY_FLAG, (a | value) & 0x08 != 0);
+    set_zsp(flags, result);
+    result
+}
+
+pub fn xor(flags: &mut u8, a: u8, value: u8) -> u8 {
+    let result = a ^ value;
+    clear_bit(flags, CARRY_FLAG);
+    clear_bit(flags, AUX_CARRY_FLAG);
+    set_zsp(flags, result);
+    result
+}
+
+pub fn or(flags: &mut u8, a: u8, value: u8) -> u8 {
+    let result = a | value;
+    clear_bit(flags, CARRY_FLAG);
+    clear_bit(flags, AUX_CARRY_FLAG);
+    set_zsp(flags, result);
+    result
+}
+
+pub fn inr(flags: &mut u8, value: u8) -> u8 {
+    let result = value.wrapping_add(1);
+    set_bit_enabled(flags, AUX_CARRY_FLAG, result & 0xF == 0);
+    set_zsp(flags, result);
+    result
+}
+
+pub fn dcr(flags: &mut u8, value: u8) -> u8 {
+    let result = value.wrapping_sub(1);
+    set_bit_enabled(flags, AUX_CARRY_FLAG, result & 0xF != 0xF);
+    set_zsp(flags, result);
+    result
+}
+
+pub fn daa(flags: &mut u8, a: u8) -> u8 {
+    let low = a & 0xF;
+    let high = a >> 4;
+    let mut correction = 0;
+    let mut carry = get_bit(*flags, CARRY_FLAG);
+
+    if low > 9 || get_bit(*flags, AUX_CARRY_FLAG) {
+        correction |= 0x06;
+    }
+    // The upper nibble is also corrected when the lower correction carries into it
+    if carry || high > 9 || (high >= 9 && low > 9) {
+        correction |= 0x60;
+        carry = true;
+    }
+
+    // The auxiliary carry comes from adding the correction, the carry is only ever set
+    let result = add(flags, a, correction, false);
+    set_bit_enabled(flags, CARRY_FLAG, carry);
+    result
+}
+
+pub fn parity(value: u8) -> bool {
+    get_enabled_bits(value) & 0x1 == 0
+}
+
+fn set_zsp(flags: &mut u8, value: u8) {
+    set_bit_enabled(flags, ZERO_FLAG, value == 0);
+    set_bit_enabled(flags, SIGN_FLAG, get_bit(value, 7));
+    set_bit_enabled(flags, PARITY_FLAG, parity(value));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const S: u8 = 0x80;
+    const Z: u8 = 0x40;
+    const AC: u8 = 0x10;
+    const P: u8 = 0x04;
+    const CY: u8 = 0x01;
+    const FIXED: u8 = 0x02;
+
+    /// Two operand cases: A, operand, carry or borrow in, result, flags after
+    type Case = (u8, u8, bool, u8, u8);
+
+    fn check_binary(name: &str, cases: &[Case], operation: fn(&mut u8, u8, u8, bool) -> u8) {
+        for &(a, value, carry, result, expected) in cases {
+            let mut flags = FIXED;
+            assert_eq!(
+                operation(&mut flags, a, value, carry),
+                result,
+                "{} {:02X},{:02X},{}",
+                name,
+                a,
+                value,
+                carry
+            );
+            assert_eq!(
+                flags, expected,
+                "{} {:02X},{:02X},{} flags {:08b}",
+                name, a, value, carry, flags
+            );
+        }
+    }
+
+    /// Logic cases start with AC and CY set: A, operand, result, flags after
+    fn check_logic(name: &str, cases: &[(u8, u8, u8, u8)], operation: fn(&mut u8, u8, u8) -> u8) {
+        for &(a, value, result, expected) in cases {
+            let mut flags = FIXED | AC | CY;
+            assert_eq!(
+                operation(&mut flags, a, value),
+                result,
+                "{} {:02X},{:02X}",
+                name,
+                a,
+                value
+            );
+            assert_eq!(
+                flags, expected,
+                "{} {:02X},{:02X} flags {:08b}",
+                name, a, value, flags
+            );
+        }
+    }
+
+    /// INR and DCR cases start with CY set, which they have to keep: operand, result, flags after
+    fn check_unary(name: &str, cases: &[(u8, u8, u8)], operation: fn(&mut u8, u8) -> u8) {
+        for &(value, result, expected) in cases {
+            let mut flags = FIXED | CY;
+            assert_eq!(
+                operation(&mut flags, value),
+                result,
+                "{} {:02X}",
+                name,
+                value
+            );
+            assert_eq!(
+                flags, expected,
+                "{} {:02X} flags {:08b}",
+                name, value, flags
+            );
+        }
+    }
+
+    #[test]
+    fn add_and_adc() {
+        check_binary(
+            "ADD",
+            &[
+                (0x00, 0x00, false, 0x00, FIXED | Z | P),
+                (0x0F, 0x01, false, 0x10, FIXED | AC),
+                (0x80, 0x80, false, 0x00, FIXED | Z | P | CY),
+                (0x2E, 0x74, false, 0xA2, FIXED | S | AC),
+                (0x7F, 0x01, false, 0x80, FIXED | S | AC),
+                (0x05, 0x03, false, 0x08, FIXED),
+                (0x03, 0x03, false, 0x06, FIXED | P),
+            ],
+            add,
+        );
+        check_binary(
+            "ADC",
+            &[
+                (0x0E, 0x01, true, 0x10, FIXED | AC),
+                (0xFF, 0x00, true, 0x00, FIXED | Z | AC | P | CY),
+                (0x3D, 0x42, true, 0x80, FIXED | S | AC),
+                (0x01, 0x01, false, 0x02, FIXED),
+            ],
+            add,
+        );
+    }
+
+    #[test]
+    fn sub_and_sbb() {
+        // The auxiliary carry is set when there is no borrow out of bit 3
+        check_binary(
+            "SUB",
+            &[
+                (0x3E, 0x3E, false, 0x00, FIXED | Z | AC | P),
+                (0x00, 0x01, false, 0xFF, FIXED | S | P | CY),
+                (0x10, 0x01, false, 0x0F, FIXED | P),
+                (0x05, 0x03, false, 0x02, FIXED | AC),
+                (0x80, 0x01, false, 0x7F, FIXED),
+            ],
+            sub,
+        );
+        check_binary(
+            "SBB",
+            &[
+                (0x04, 0x02, true, 0x01, FIXED | AC),
+                (0x00, 0x00, true, 0xFF, FIXED | S | P | CY),
+                (0x10, 0x0F, true, 0x00, FIXED | Z | P),
+            ],
+            sub,
+        );
+    }
+
+    #[test]
+    fn cmp() {
+        // CMP is a SUB that only keeps the flags
+        check_binary(
+            "CMP",
+            &[
+                (0x0A, 0x05, false, 0x05, FIXED | AC | P),
+                (0x02, 0x05, false, 0xFD, FIXED | S | CY),
+                (0x42, 0x42, false, 0x00, FIXED | Z | AC | P),
+            ],
+            sub,
+        );
+    }
+
+    #[test]
+    fn ana() {
+        check_logic(
+            "ANA",
+            &[
+                (0xFC, 0x0F, 0x0C, FIXED | AC | P),
+                (0xF0, 0x07, 0x00, FIXED | Z | P),
+                (0x80, 0x88, 0x80, FIXED | S | AC),
+            ],
+            and,
+        );
+    }
+
+    #[test]
+    fn xra_and_ora() {
+        check_logic(
+            "XRA",
+            &[
+                (0x5A, 0x5A, 0x00, FIXED | Z | P),
+                (0x3B, 0x81, 0xBA, FIXED | S),
+                (0x01, 0x02, 0x03, FIXED | P),
+            ],
+            xor,
+        );
+        check_logic(
+            "ORA",
+            &[
+                (0x33, 0x0F, 0x3F, FIXED | P),
+                (0x00, 0x00, 0x00, FIXED | Z | P),
+                (0x80, 0x01, 0x81, FIXED | S | P),
+            ],
+            or,
+        );
+    }
+
+    #[test]
+    fn inr_and_dcr() {
+        check_unary(
+            "INR",
+            &[
+                (0xFF, 0x00, FIXED | Z | AC | P | CY),
+                (0x0F, 0x10, FIXED | AC | CY),
+                (0x7F, 0x80, FIXED | S | AC | CY),
+                (0x02, 0x03, FIXED | P | CY),
+                (0x00, 0x01, FIXED | CY),
+            ],
+            inr,
+        );
+        check_unary(
+            "DCR",
+            &[
+                (0x00, 0xFF, FIXED | S | P | CY),
+                (0x01, 0x00, FIXED | Z | AC | P | CY),
+                (0x10, 0x0F, FIXED | P | CY),
+                (0x80, 0x7F, FIXED | CY),
+                (0x04, 0x03, FIXED | AC | P | CY),
+            ],
+            dcr,
+        );
+    }
+
+    #[test]
+    fn parity_counts_set_bits() {
+        assert!(parity(0x00));
+        assert!(parity(0xFF));
+        assert!(parity(0x03));
+        assert!(!parity(0x01));
+        assert!(!parity(0x80));
+        assert!(!parity(0xFE));
+    }
+}
@@ -0,0 +1,1019 @@
+use super::bus::*;
+use super::decoder::*;
+
+use std::collections::{BTreeMap, HashMap};
+use std::error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembleError {
+    /// 1-based source line the error was found on
+    pub line: usize,
+    pub message: String,
+}
+
+impl AssembleError {
+    fn new(line: usize, message: impl Into<String>) -> Self {
+        Self {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl error::Error for AssembleError {}
+
+/// A contiguous run of assembled bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub segments: Vec<Segment>,
+    /// Labels and EQU values, upper case
+    pub symbols: BTreeMap<String, u16>,
+}
+
+impl Program {
+    pub fn load(&self, bus: &mut Bus) -> Result<(), BusError> {
+        for segment in &self.segments {
+            bus.load_bytes(segment.address, &segment.bytes)?;
+        }
+        Ok(())
+    }
+}
+
+/// Assembles Intel 8080 source in two passes.
+///
+/// Supported are labels (with or without a colon), `ORG`, `DB`, `DW`, `DS`, `EQU`,
+/// `SET` and `END`, numbers in decimal or with `H`, `B`, `O`/`Q` suffix or `$` prefix,
+/// character constants, `$` for the current address and the operators
+/// `+ - * / MOD SHL SHR NOT AND OR XOR HIGH LOW`.
+pub fn assemble(source: &str) -> Result<Program, AssembleError> {
+    let opcodes = OpcodeMap::new();
+    let statements = source
+        .lines()
+        .enumerate()
+        .map(|(i, text)| parse_statement(i + 1, text))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let symbols = first_pass(&statements, &opcodes)?;
+    second_pass(&statements, &opcodes, symbols)
+}
+
+/// The instruction encodings, derived from the decoder's opcode table so the two can't drift apart
+struct OpcodeMap {
+    /// Mnemonic and register operands to opcode, aliases map to the documented opcode
+    opcodes: HashMap<(String, Vec<String>), u8>,
+    /// Mnemonic to instruction size and number of register operands
+    shapes: HashMap<String, (u16, usize)>,
+}
+
+impl OpcodeMap {
+    fn new() -> Self {
+        let mut opcodes = HashMap::new();
+        let mut shapes = HashMap::new();
+
+        for (opcode, template) in OPCODE_TABLE.iter().enumerate() {
+            let mnemonic = template.mnemonic().to_string();
+            let registers = match template {
+                Instruction::Rst(n) => vec![n.to_string()],
+                _ => register_names(template),
+            };
+            shapes
+                .entry(mnemonic.clone())
+                .or_insert((template.size(), registers.len()));
+            opcodes.entry((mnemonic, registers)).or_insert(opcode as u8);
+        }
+
+        Self { opcodes, shapes }
+    }
+}
+
+fn register_names(template: &Instruction) -> Vec<String> {
+    match template {
+        Instruction::Push(Register::A) | Instruction::Pop(Register::A) => vec!["PSW".to_string()],
+        _ => template
+            .registers()
+            .iter()
+            .map(|reg| reg.to_string())
+            .collect(),
+    }
+}
+
+struct Statement<'a> {
+    line: usize,
+    label: Option<String>,
+    operation: Option<String>,
+    operands: Vec<&'a str>,
+}
+
+const DIRECTIVES: [&str; 7] = ["ORG", "DB", "DW", "DS", "EQU", "SET", "END"];
+
+fn parse_statement(line: usize, text: &str) -> Result<Statement<'_>, AssembleError> {
+    let text = strip_comment(text);
+    let starts_in_first_column = !text.starts_with(|c: char| c.is_whitespace());
+    let text = text.trim();
+
+    // A colon ends the label even if the operation follows without a space, as in LOOP:MOV A,B
+    let (label, body) = if let Some(colon) = find_unquoted(text, ':') {
+        (Some(text[..colon].trim()), &text[colon + 1..])
+    } else {
+        let (first, rest) = split_word(text);
+        let (second, _) = split_word(rest);
+        if ["EQU", "SET"].contains(&second.to_uppercase().as_str())
+            || (starts_in_first_column && !first.is_empty() && !is_operation(first))
+        {
+            (Some(first), rest)
+        } else {
+            (None, text)
+        }
+    };
+
+    if let Some(name) = label {
+        if !is_identifier(name) {
+            return Err(AssembleError::new(
+                line,
+                format!("invalid label '{}'", name),
+            ));
+        }
+    }
+
+    let (operation, operands) = split_word(body);
+    let operands = if operands.trim().is_empty() {
+        Vec::new()
+    } else {
+        split_operands(operands.trim())
+    };
+
+    Ok(Statement {
+        line,
+        label: label.map(|name| name.to_uppercase()),
+        operation: if operation.is_empty() {
+            None
+        } else {
+            Some(operation.to_uppercase())
+        },
+        operands,
+    })
+}
+
+fn is_operation(word: &str) -> bool {
+    let word = word.to_uppercase();
+    DIRECTIVES.contains(&word.as_str())
+        || OPCODE_TABLE
+            .iter()
+            .any(|template| template.mnemonic() == word)
+}
+
+fn is_identifier(word: &str) -> bool {
+    word.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '?' || c == '@')
+        && word.chars().all(is_identifier_char)
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '?' || c == '@' || c == '.'
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    match text.find(char::is_whitespace) {
+        Some(end) => (&text[..end], &text[end..]),
+        None => (text, ""),
+    }
+}
+
+fn strip_comment(text: &str) -> &str {
+    match find_unquoted(text, ';') {
+        Some(end) => &text[..end],
+        None => text,
+    }
+}
+
+/// Position of the first `target` that is not inside a quoted string
+fn find_unquoted(text: &str, target: char) -> Option<usize> {
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        if c == '\'' {
+            quoted = !quoted;
+        } else if c == target && !quoted {
+            return Some(i);
+        }
+    }
+    None
+}
+
+fn split_operands(text: &str) -> Vec<&str> {
+    let mut operands = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            ',' if !quoted => {
+                operands.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    operands.push(text[start..].trim());
+    operands
+}
+
+/// Returns the contents of a quoted string operand with doubled quotes collapsed.
+/// Operands like `'A' OR 'B'` start and end with a quote but are expressions.
+fn string_literal(operand: &str) -> Option<String> {
+    let contents = operand.strip_prefix('\'')?.strip_suffix('\'')?;
+    let text = contents.replace("''", "'");
+    if contents.replace("''", "").contains('\'') {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+fn first_pass(
+    statements: &[Statement],
+    opcodes: &OpcodeMap,
+) -> Result<HashMap<String, i64>, AssembleError> {
+    let mut symbols = HashMap::new();
+    let mut pending_equates = Vec::new();
+    let mut address: i64 = 0;
+
+    for statement in statements {
+        let line = statement.line;
+        let operation = statement.operation.as_deref();
+
+        if let Some(label) = &statement.label {
+            if matches!(operation, Some("EQU") | Some("SET")) {
+                match evaluate(&statement.operands, 0, line, address, &symbols) {
+                    Ok(value) => define(&mut symbols, label, value, operation, line)?,
+                    Err(_) if operation == Some("EQU") => {
+                        pending_equates.push((statement, address))
+                    }
+                    Err(error) => return Err(error),
+                }
+                continue;
+            }
+            define(&mut symbols, label, address, None, line)?;
+        }
+
+        let operation = match operation {
+            Some(operation) => operation,
+            None => continue,
+        };
+
+        address += match operation {
+            "ORG" => {
+                address = expect_value(&statement.operands, line, address, &symbols)?;
+                check_address(address, line)?;
+                0
+            }
+            "DS" => reserve(&statement.operands, line, address, &symbols)?,
+            "DB" => statement
+                .operands
+                .iter()
+                .map(|operand| match string_literal(operand) {
+                    Some(text) if text.len() != 1 => text.len() as i64,
+                    _ => 1,
+                })
+                .sum(),
+            "DW" => 2 * statement.operands.len() as i64,
+            "END" => break,
+            "EQU" | "SET" => {
+                return Err(AssembleError::new(
+                    line,
+                    format!("{} needs a name", operation),
+                ))
+            }
+            mnemonic => match opcodes.shapes.get(mnemonic) {
+                Some((size, _)) => *size as i64,
+                None => {
+                    return Err(AssembleError::new(
+                        line,
+                        format!("unknown instruction '{}'", mnemonic),
+                    ))
+                }
+            },
+        };
+
+        if address > MEMORY_SIZE as i64 {
+            return Err(AssembleError::new(line, "program runs past FFFFh"));
+        }
+    }
+
+    // EQUs may refer to names defined further down, resolve them once all labels are known.
+    // `$` still means the address at the EQU.
+    while !pending_equates.is_empty() {
+        let before = pending_equates.len();
+        let mut last_error = None;
+        pending_equates.retain(|(statement, address)| {
+            match evaluate(&statement.operands, 0, statement.line, *address, &symbols) {
+                Ok(value) => {
+                    symbols.insert(statement.label.clone().unwrap(), value);
+                    false
+                }
+                Err(error) => {
+                    last_error = Some(error);
+                    true
+                }
+            }
+        });
+        if pending_equates.len() == before {
+            return Err(last_error.unwrap());
+        }
+    }
+
+    Ok(symbols)
+}
+
+fn define(
+    symbols: &mut HashMap<String, i64>,
+    name: &str,
+    value: i64,
+    operation: Option<&str>,
+    line: usize,
+) -> Result<(), AssembleError> {
+    if symbols.contains_key(name) && operation != Some("SET") {
+        return Err(AssembleError::new(
+            line,
+            format!("'{}' is already defined", name),
+        ));
+    }
+    symbols.insert(name.to_string(), value);
+    Ok(())
+}
+
+fn second_pass(
+    statements: &[Statement],
+    opcodes: &OpcodeMap,
+    mut symbols: HashMap<String, i64>,
+) -> Result<Program, AssembleError> {
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut address: i64 = 0;
+
+    for statement in statements {
+        let line = statement.line;
+        let operands = &statement.operands;
+        let operation = match statement.operation.as_deref() {
+            Some(operation) => operation,
+            None => continue,
+        };
+
+        let mut bytes = Vec::new();
+        match operation {
+            "ORG" => {
+                address = expect_value(operands, line, address, &symbols)?;
+                continue;
+            }
+            "DS" => {
+                address += reserve(operands, line, address, &symbols)?;
+                continue;
+            }
+            "SET" => {
+                let value = evaluate(operands, 0, line, address, &symbols)?;
+                symbols.insert(statement.label.clone().unwrap(), value);
+                continue;
+            }
+            "EQU" => continue,
+            "END" => break,
+            "DB" => {
+                for (i, operand) in operands.iter().enumerate() {
+                    match string_literal(operand) {
+                        Some(text) if text.len() != 1 => bytes.extend(text.bytes()),
+                        _ => {
+                            bytes.push(byte(evaluate(operands, i, line, address, &symbols)?, line)?)
+                        }
+                    }
+                }
+            }
+            "DW" => {
+                for i in 0..operands.len() {
+                    let value = word(evaluate(operands, i, line, address, &symbols)?, line)?;
+                    bytes.extend(&value.to_le_bytes());
+                }
+            }
+            mnemonic => {
+                bytes = encode(mnemonic, operands, opcodes, line, address, &symbols)?;
+            }
+        }
+
+        match segments.last_mut() {
+            Some(segment) if segment.address as i64 + segment.bytes.len() as i64 == address => {
+                segment.bytes.extend(&bytes)
+            }
+            _ => segments.push(Segment {
+                address: address as u16,
+                bytes: bytes.clone(),
+            }),
+        }
+        address += bytes.len() as i64;
+    }
+
+    segments.retain(|segment| !segment.bytes.is_empty());
+    let symbols = symbols
+        .into_iter()
+        .map(|(name, value)| (name, value as u16))
+        .collect();
+
+    Ok(Program { segments, symbols })
+}
+
+fn encode(
+    mnemonic: &str,
+    operands: &[&str],
+    opcodes: &OpcodeMap,
+    line: usize,
+    address: i64,
+    symbols: &HashMap<String, i64>,
+) -> Result<Vec<u8>, AssembleError> {
+    let (size, register_count) = opcodes.shapes[mnemonic];
+    let expected = if mnemonic == "RST" {
+        1
+    } else {
+        register_count + (size > 1) as usize
+    };
+    if operands.len() != expected {
+        return Err(AssembleError::new(
+            line,
+            format!(
+                "{} takes {} operands, found {}",
+                mnemonic,
+                expected,
+                operands.len()
+            ),
+        ));
+    }
+
+    let registers: Vec<String> = if mnemonic == "RST" {
+        vec![evaluate(operands, 0, line, address, symbols)?.to_string()]
+    } else {
+        operands[..register_count]
+            .iter()
+            .map(|operand| operand.to_uppercase())
+            .collect()
+    };
+
+    let opcode = opcodes
+        .opcodes
+        .get(&(mnemonic.to_string(), registers.clone()))
+        .ok_or_else(|| {
+            AssembleError::new(
+                line,
+                format!("invalid operands {} for {}", registers.join(","), mnemonic),
+            )
+        })?;
+
+    let mut bytes = vec![*opcode];
+    match size {
+        2 => bytes.push(byte(
+            evaluate(operands, register_count, line, address, symbols)?,
+            line,
+        )?),
+        3 => bytes.extend(
+            &word(
+                evaluate(operands, register_count, line, address, symbols)?,
+                line,
+            )?
+            .to_le_bytes(),
+        ),
+        _ => {}
+    }
+    Ok(bytes)
+}
+
+fn byte(value: i64, line: usize) -> Result<u8, AssembleError> {
+    if (-128..=255).contains(&value) {
+        Ok(value as u8)
+    } else {
+        Err(AssembleError::new(
+            line,
+            format!("{} does not fit in a byte", value),
+        ))
+    }
+}
+
+fn word(value: i64, line: usize) -> Result<u16, AssembleError> {
+    if (-32768..=65535).contains(&value) {
+        Ok(value as u16)
+    } else {
+        Err(AssembleError::new(
+            line,
+            format!("{} does not fit in a word", value),
+        ))
+    }
+}
+
+fn check_address(address: i64, line: usize) -> Result<(), AssembleError> {
+    if (0..MEMORY_SIZE as i64).contains(&address) {
+        Ok(())
+    } else {
+        Err(AssembleError::new(
+            line,
+            format!("address {} is out of range", address),
+        ))
+    }
+}
+
+/// Number of bytes a `DS` skips
+fn reserve(
+    operands: &[&str],
+    line: usize,
+    address: i64,
+    symbols: &HashMap<String, i64>,
+) -> Result<i64, AssembleError> {
+    let size = expect_value(operands, line, address, symbols)?;
+    if size < 0 {
+        return Err(AssembleError::new(
+            line,
+            format!("DS size {} is negative", size),
+        ));
+    }
+    Ok(size)
+}
+
+fn expect_value(
+    operands: &[&str],
+    line: usize,
+    address: i64,
+    symbols: &HashMap<String, i64>,
+) -> Result<i64, AssembleError> {
+    if operands.len() != 1 {
+        return Err(AssembleError::new(line, "expected a single operand"));
+    }
+    evaluate(operands, 0, line, address, symbols)
+}
+
+fn evaluate(
+    operands: &[&str],
+    index: usize,
+    line: usize,
+    address: i64,
+    symbols: &HashMap<String, i64>,
+) -> Result<i64, AssembleError> {
+    let text = operands
+        .get(index)
+        .ok_or_else(|| AssembleError::new(line, "missing operand"))?;
+    let tokens = tokenize(text).map_err(|message| AssembleError::new(line, message))?;
+    let mut parser = ExpressionParser {
+        tokens: &tokens,
+        position: 0,
+        address,
+        symbols,
+    };
+    let value = parser
+        .or_expression()
+        .map_err(|message| AssembleError::new(line, message))?;
+    if parser.position != tokens.len() {
+        return Err(AssembleError::new(
+            line,
+            format!("unexpected text in '{}'", text),
+        ));
+    }
+    Ok(value)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Location,
+    Operator(char),
+    Open,
+    Close,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '\'' {
+            let mut value: i64 = 0;
+            let mut length = 0;
+            i += 1;
+            loop {
+                match (chars.get(i), chars.get(i + 1)) {
+                    (Some('\''), Some('\'')) => i += 1,
+                    (Some('\''), _) => break,
+                    (None, _) => return Err("unterminated character constant".to_string()),
+                    _ => {}
+                }
+                value = (value << 8) | (chars[i] as i64 & 0xFF);
+                length += 1;
+                i += 1;
+            }
+            i += 1;
+            if length == 0 || length > 2 {
+                return Err("character constants hold one or two characters".to_string());
+            }
+            tokens.push(Token::Number(value));
+        } else if c == '$' {
+            let start = i + 1;
+            let mut end = start;
+            while end < chars.len() && chars[end].is_ascii_hexdigit() {
+                end += 1;
+            }
+            if end == start {
+                tokens.push(Token::Location);
+            } else {
+                let digits: String = chars[start..end].iter().collect();
+                tokens.push(Token::Number(i64::from_str_radix(&digits, 16).unwrap()));
+            }
+            i = end;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && chars[i].is_ascii_alphanumeric() {
+                i += 1;
+            }
+            let literal: String = chars[start..i].iter().collect();
+            tokens.push(Token::Number(parse_number(&literal)?));
+        } else if is_identifier_char(c) {
+            let start = i;
+            while i < chars.len() && is_identifier_char(chars[i]) {
+                i += 1;
+            }
+            let name: String = chars[start..i].iter().collect();
+            tokens.push(Token::Name(name.to_uppercase()));
+        } else {
+            tokens.push(match c {
+                '(' => Token::Open,
+                ')' => Token::Close,
+                '+' | '-' | '*' | '/' => Token::Operator(c),
+                _ => return Err(format!("unexpected character '{}'", c)),
+            });
+            i += 1;
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number(literal: &str) -> Result<i64, String> {
+    let upper = literal.to_uppercase();
+    let (digits, radix) = match upper.chars().last() {
+        Some('H') => (&upper[..upper.len() - 1], 16),
+        Some('B') => (&upper[..upper.len() - 1], 2),
+        Some('O') | Some('Q') => (&upper[..upper.len() - 1], 8),
+        Some('D') => (&upper[..upper.len() - 1], 10),
+        _ => (upper.as_str(), 10),
+    };
+    i64::from_str_radix(digits, radix).map_err(|_| format!("invalid number '{}'", literal))
+}
+
+struct ExpressionParser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    address: i64,
+    symbols: &'a HashMap<String, i64>,
+}
+
+impl<'a> ExpressionParser<'a> {
+    fn peek_name(&self) -> Option<&str> {
+        match self.tokens.get(self.position) {
+            Some(Token::Name(name)) => Some(name.as_str()),
+            _ => None,
+        }
+    }
+
+    fn accept_name(&mut self, name: &str) -> bool {
+        if self.peek_name() == Some(name) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn accept(&mut self, token: Token) -> bool {
+        if self.tokens.get(self.position) == Some(&token) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or_expression(&mut self) -> Result<i64, String> {
+        let mut value = self.and_expression()?;
+        loop {
+            if self.accept_name("OR") {
+                value |= self.and_expression()?;
+            } else if self.accept_name("XOR") {
+                value ^= self.and_expression()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn and_expression(&mut self) -> Result<i64, String> {
+        let mut value = self.not_expression()?;
+        while self.accept_name("AND") {
+            value &= self.not_expression()?;
+        }
+        Ok(value)
+    }
+
+    fn not_expression(&mut self) -> Result<i64, String> {
+        if self.accept_name("NOT") {
+            Ok(!self.not_expression()? & 0xFFFF)
+        } else {
+            self.additive()
+        }
+    }
+
+    fn additive(&mut self) -> Result<i64, String> {
+        let mut value = self.multiplicative()?;
+        loop {
+            if self.accept(Token::Operator('+')) {
+                value += self.multiplicative()?;
+            } else if self.accept(Token::Operator('-')) {
+                value -= self.multiplicative()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<i64, String> {
+        let mut value = self.unary()?;
+        loop {
+            if self.accept(Token::Operator('*')) {
+                value *= self.unary()?;
+            } else if self.accept(Token::Operator('/')) || self.accept_name("MOD") {
+                let divide = self.tokens[self.position - 1] == Token::Operator('/');
+                let divisor = self.unary()?;
+                if divisor == 0 {
+                    return Err("division by zero".to_string());
+                }
+                value = if divide {
+                    value / divisor
+                } else {
+                    value % divisor
+                };
+            } else if self.accept_name("SHL") {
+                value = (value << (self.unary()? & 0x1F)) & 0xFFFF;
+            } else if self.accept_name("SHR") {
+                value = (value & 0xFFFF) >> (self.unary()? & 0x1F);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        if self.accept(Token::Operator('-')) {
+            Ok(-self.unary()?)
+        } else if self.accept(Token::Operator('+')) {
+            self.unary()
+        } else if self.accept_name("HIGH") {
+            Ok((self.unary()? >> 8) & 0xFF)
+        } else if self.accept_name("LOW") {
+            Ok(self.unary()? & 0xFF)
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<i64, String> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or_else(|| "expression ends unexpectedly".to_string())?;
+        self.position += 1;
+
+        match token {
+            Token::Number(value) => Ok(value),
+            Token::Location => Ok(self.address),
+            Token::Name(name) => self
+                .symbols
+                .get(&name)
+                .copied()
+                .ok_or_else(|| format!("undefined symbol '{}'", name)),
+            Token::Open => {
+                let value = self.or_expression()?;
+                if self.accept(Token::Close) {
+                    Ok(value)
+                } else {
+                    Err("missing ')'".to_string())
+                }
+            }
+            Token::Close | Token::Operator(_) => Err("expected a value".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::disassembler::*;
+    use super::*;
+
+    /// The single segment `source` assembles to
+    fn bytes(source: &str) -> Vec<u8> {
+        let program = assemble(source).unwrap();
+        assert_eq!(program.segments.len(), 1, "{:?}", program.segments);
+        program.segments[0].bytes.clone()
+    }
+
+    fn symbol(source: &str, name: &str) -> u16 {
+        assemble(source).unwrap().symbols[name]
+    }
+
+    fn error(source: &str) -> AssembleError {
+        assemble(source).unwrap_err()
+    }
+
+    /// Opcodes the assembler never emits because they alias documented instructions
+    const UNDOCUMENTED: [u8; 12] = [
+        0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38, 0xCB, 0xD9, 0xDD, 0xED, 0xFD,
+    ];
+
+    #[test]
+    fn every_opcode_round_trips() {
+        for opcode in 0..=0xFFu8 {
+            let original = [opcode, 0x34, 0x12];
+            let (instruction, size) = decode(&original).unwrap();
+            let text = instruction.to_string();
+
+            let assembled = bytes(&format!("    {}", text));
+            let (reassembled, _) = decode(&assembled).unwrap();
+            assert_eq!(reassembled, instruction, "{}", text);
+            assert_eq!(reassembled.to_string(), text);
+            if !UNDOCUMENTED.contains(&opcode) {
+                assert_eq!(assembled, original[..size], "{}", text);
+            }
+        }
+    }
+
+    #[test]
+    fn program_round_trips_through_the_disassembler() {
+        let source = "
+        ORG 100h
+START:  LXI SP,STACK
+        MVI C,COUNT
+LOOP:   CALL PRINT
+        DCR C
+        JNZ LOOP
+        HLT
+PRINT:  PUSH PSW
+        MOV A,C
+        OUT 1
+        POP PSW
+        RET
+COUNT   EQU 3
+STACK   EQU 0F000h
+";
+        let program = assemble(source).unwrap();
+        let mut bus = Bus::new();
+        program.load(&mut bus).unwrap();
+
+        let listing: Vec<String> = Disassembler::new(HexStyle::Intel)
+            .linear_sweep(&bus, 0x100, 0x112)
+            .iter()
+            .map(|line| line.to_string())
+            .collect();
+        assert_eq!(
+            listing,
+            [
+                "0100  31 00 F0  LXI SP,0F000h",
+                "0103  0E 03     MVI C,03h",
+                "0105  CD 0D 01  CALL 010Dh",
+                "0108  0D        DCR C",
+                "0109  C2 05 01  JNZ 0105h",
+                "010C  76        HLT",
+                "010D  F5        PUSH PSW",
+                "010E  79        MOV A,C",
+                "010F  D3 01     OUT 01h",
+                "0111  F1        POP PSW",
+                "0112  C9        RET",
+            ]
+        );
+        assert_eq!(program.symbols["LOOP"], 0x105);
+    }
+
+    #[test]
+    fn expressions() {
+        let cases = [
+            ("1+2*3", 7),
+            ("(1+2)*3", 9),
+            ("10/3", 3),
+            ("10 MOD 3", 1),
+            ("1 SHL 4", 0x10),
+            ("80H SHR 4", 0x08),
+            ("NOT 0", 0xFFFF),
+            ("0F0H AND 3CH", 0x30),
+            ("0F0H OR 0FH", 0xFF),
+            ("0FFH XOR 0FH", 0xF0),
+            ("HIGH 1234H", 0x12),
+            ("LOW 1234H", 0x34),
+            ("-1", 0xFFFF),
+            ("2-3+4", 3),
+            ("'A'", 0x41),
+            ("'AB'", 0x4142),
+            ("''''", 0x27),
+            ("$1F", 0x1F),
+            ("101B", 5),
+            ("17Q", 15),
+            ("17O", 15),
+            ("99D", 99),
+            ("$+2", 0x102),
+        ];
+        for (expression, value) in cases.iter() {
+            let source = format!(" ORG 100h\nX EQU {}", expression);
+            assert_eq!(symbol(&source, "X"), *value, "{}", expression);
+        }
+    }
+
+    #[test]
+    fn equ_refers_forward() {
+        let source = "
+SIZE    EQU LAST-FIRST
+        ORG 100h
+        MVI A,SIZE
+FIRST:  DB 1,2,3
+LAST:
+A2      EQU B2+1
+B2      EQU C2*2
+C2      EQU 5
+";
+        assert_eq!(bytes(source), [0x3E, 0x03, 0x01, 0x02, 0x03]);
+        assert_eq!(symbol(source, "A2"), 11);
+    }
+
+    #[test]
+    fn deferred_equ_takes_dollar_from_its_own_line() {
+        let source = "
+        ORG 100h
+HERE    EQU $+LATER
+        DB 1,2,3
+LATER   EQU 2
+";
+        assert_eq!(symbol(source, "HERE"), 0x102);
+    }
+
+    #[test]
+    fn negative_ds_is_rejected() {
+        let error = error(" ORG 100h\n DS 1\n DS 2-3");
+        assert_eq!(error.line, 3);
+        assert!(error.message.contains("negative"), "{}", error);
+        assert_eq!(bytes(" DS 0\n DB 1"), [0x01]);
+    }
+
+    #[test]
+    fn labels_without_space_after_colon() {
+        assert_eq!(bytes("LOOP:MOV A,B\n JMP LOOP"), [0x78, 0xC3, 0x00, 0x00]);
+        assert_eq!(bytes(" NOP\nNEXT:\tJMP NEXT"), [0x00, 0xC3, 0x01, 0x00]);
+        assert_eq!(symbol(" NOP\n    START:NOP", "START"), 1);
+        assert_eq!(bytes("C:MVI A,':'"), [0x3E, b':']);
+    }
+
+    #[test]
+    fn db_strings_and_expressions() {
+        assert_eq!(
+            bytes(" DB 'A' OR 'B', 'AB', 'it''s', '''', 'A'+1"),
+            [0x43, b'A', b'B', b'i', b't', b'\'', b's', b'\'', 0x42]
+        );
+        assert_eq!(bytes(" DB 'A'' OR ''B'"), b"A' OR 'B");
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let cases = [
+            (" NOP\n FOO A", 2, "unknown instruction 'FOO'"),
+            (" MVI A,300", 1, "300 does not fit in a byte"),
+            ("X: NOP\n\nX: NOP", 3, "'X' is already defined"),
+            (" NOP\n JMP NOWHERE", 2, "undefined symbol 'NOWHERE'"),
+            (" MOV A", 1, "MOV takes 2 operands, found 1"),
+            (" MOV A,Q", 1, "invalid operands A,Q for MOV"),
+            (" ORG 0FFFFh\n LXI H,0", 2, "program runs past FFFFh"),
+            ("X EQU Y\nY EQU X", 2, "undefined symbol 'X'"),
+            ("1X: NOP", 1, "invalid label '1X'"),
+            (" DB 'AB", 1, "unterminated character constant"),
+            (" NOP\n MVI A,(1", 2, "missing ')'"),
+            (" DB 1/0", 1, "division by zero"),
+        ];
+        for (source, line, message) in cases.iter() {
+            assert_eq!(
+                error(source),
+                AssembleError::new(*line, *message),
+                "{:?}",
+                source
+            );
+        }
+    }
+}
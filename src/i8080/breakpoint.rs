@@ -0,0 +1,576 @@
+use super::cpu::*;
+
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn matches(&self, access: Access) -> bool {
+        *self == Access::ReadWrite || *self == access
+    }
+}
+
+/// Watches a range of memory addresses or I/O ports
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub access: Access,
+}
+
+impl Watchpoint {
+    pub fn new(range: RangeInclusive<u16>, access: Access) -> Self {
+        let (start, end) = range.into_inner();
+        Self { start, end, access }
+    }
+
+    /// Whether a read or write of `address` triggers the watchpoint
+    pub fn matches(&self, address: u16, access: Access) -> bool {
+        self.start <= address && address <= self.end && self.access.matches(access)
+    }
+}
+
+/// An access that matched a watchpoint
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WatchHit {
+    /// Memory address or I/O port
+    pub address: u16,
+    pub value: u8,
+    /// Either `Read` or `Write`
+    pub access: Access,
+}
+
+/// Watchpoints of a bus. The first access that matches is latched
+/// until the executor collects it after the instruction.
+pub struct WatchList {
+    watchpoints: Vec<Watchpoint>,
+    hit: Option<WatchHit>,
+}
+
+impl WatchList {
+    pub fn new() -> Self {
+        Self {
+            watchpoints: Vec::new(),
+            hit: None,
+        }
+    }
+
+    pub fn add(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn remove(&mut self, watchpoint: &Watchpoint) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|w| w != watchpoint);
+        self.watchpoints.len() != count
+    }
+
+    pub fn clear(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn check(&mut self, address: u16, value: u8, access: Access) {
+        if self.hit.is_none() && self.watchpoints.iter().any(|w| w.matches(address, access)) {
+            self.hit = Some(WatchHit {
+                address,
+                value,
+                access,
+            });
+        }
+    }
+
+    pub fn take_hit(&mut self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+}
+
+impl Default for WatchList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Registers and register pairs that conditions can test
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RegisterName {
+    A,
+    F,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+    PSW,
+}
+
+impl RegisterName {
+    /// Whether the register holds a single byte
+    pub fn is_byte(&self) -> bool {
+        matches!(
+            self,
+            RegisterName::A
+                | RegisterName::F
+                | RegisterName::B
+                | RegisterName::C
+                | RegisterName::D
+                | RegisterName::E
+                | RegisterName::H
+                | RegisterName::L
+        )
+    }
+
+    pub fn get(&self, cpu: &CPU) -> u16 {
+        match self {
+            RegisterName::A => cpu.a as u16,
+            RegisterName::F => cpu.flags as u16,
+            RegisterName::B => cpu.b as u16,
+            RegisterName::C => cpu.c as u16,
+            RegisterName::D => cpu.d as u16,
+            RegisterName::E => cpu.e as u16,
+            RegisterName::H => cpu.h as u16,
+            RegisterName::L => cpu.l as u16,
+            RegisterName::BC => cpu.get_bc(),
+            RegisterName::DE => cpu.get_de(),
+            RegisterName::HL => cpu.get_hl(),
+            RegisterName::SP => cpu.sp,
+            RegisterName::PC => cpu.pc,
+            RegisterName::PSW => cpu.get_psw(),
+        }
+    }
+
+    /// Sets the register, byte registers only take the low byte of `value`
+    pub fn set(&self, cpu: &mut CPU, value: u16) {
+        let byte = value as u8;
+        match self {
+            RegisterName::A => cpu.a = byte,
+            RegisterName::F => cpu.set_flags(byte),
+            RegisterName::B => cpu.b = byte,
+            RegisterName::C => cpu.c = byte,
+            RegisterName::D => cpu.d = byte,
+            RegisterName::E => cpu.e = byte,
+            RegisterName::H => cpu.h = byte,
+            RegisterName::L => cpu.l = byte,
+            RegisterName::BC => cpu.set_bc(value),
+            RegisterName::DE => cpu.set_de(value),
+            RegisterName::HL => cpu.set_hl(value),
+            RegisterName::SP => cpu.sp = value,
+            RegisterName::PC => cpu.pc = value,
+            RegisterName::PSW => cpu.set_psw(value),
+        }
+    }
+}
+
+impl fmt::Display for RegisterName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl FromStr for RegisterName {
+    type Err = ConditionError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let register = match text.to_ascii_uppercase().as_str() {
+            "A" => RegisterName::A,
+            "F" => RegisterName::F,
+            "B" => RegisterName::B,
+            "C" => RegisterName::C,
+            "D" => RegisterName::D,
+            "E" => RegisterName::E,
+            "H" => RegisterName::H,
+            "L" => RegisterName::L,
+            "BC" => RegisterName::BC,
+            "DE" => RegisterName::DE,
+            "HL" => RegisterName::HL,
+            "SP" => RegisterName::SP,
+            "PC" => RegisterName::PC,
+            "PSW" => RegisterName::PSW,
+            _ => return Err(ConditionError::UnknownRegister(text.to_string())),
+        };
+        Ok(register)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// Operators in the order they are searched for, so that `<=` is not taken for `<`
+const OPERATORS: [(&str, Comparison); 6] = [
+    ("==", Comparison::Equal),
+    ("!=", Comparison::NotEqual),
+    ("<=", Comparison::LessOrEqual),
+    (">=", Comparison::GreaterOrEqual),
+    ("<", Comparison::Less),
+    (">", Comparison::Greater),
+];
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (symbol, _) = OPERATORS.iter().find(|(_, c)| c == self).unwrap();
+        write!(f, "{}", symbol)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConditionError {
+    UnknownRegister(String),
+    MissingOperator,
+    InvalidValue(String),
+}
+
+impl fmt::Display for ConditionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConditionError::UnknownRegister(name) => write!(f, "unknown register '{}'", name),
+            ConditionError::MissingOperator => {
+                write!(f, "expected one of == != < <= > >=")
+            }
+            ConditionError::InvalidValue(value) => write!(f, "invalid value '{}'", value),
+        }
+    }
+}
+
+impl error::Error for ConditionError {}
+
+/// A test on a register value like `HL == 0x2400`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Condition {
+    pub register: RegisterName,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+impl Condition {
+    pub fn holds(&self, cpu: &CPU) -> bool {
+        let actual = self.register.get(cpu);
+        match self.comparison {
+            Comparison::Equal => actual == self.value,
+            Comparison::NotEqual => actual != self.value,
+            Comparison::Less => actual < self.value,
+            Comparison::LessOrEqual => actual <= self.value,
+            Comparison::Greater => actual > self.value,
+            Comparison::GreaterOrEqual => actual >= self.value,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let digits = if self.register.is_byte() { 2 } else { 4 };
+        write!(
+            f,
+            "{} {} {:0width$X}h",
+            self.register,
+            self.comparison,
+            self.value,
+            width = digits
+        )
+    }
+}
+
+/// Parses `<register> <operator> <value>`. Values are decimal unless written as `0x2400` or `2400h`.
+impl FromStr for Condition {
+    type Err = ConditionError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (position, symbol, comparison) = OPERATORS
+            .iter()
+            .filter_map(|(symbol, comparison)| {
+                text.find(symbol)
+                    .map(|position| (position, *symbol, *comparison))
+            })
+            .min_by_key(|(position, _, _)| *position)
+            .ok_or(ConditionError::MissingOperator)?;
+
+        let register: RegisterName = text[..position].trim().parse()?;
+        let value_text = text[position + symbol.len()..].trim();
+        let value = parse_value(value_text)
+            .filter(|value| !register.is_byte() || *value <= 0xFF)
+            .ok_or_else(|| ConditionError::InvalidValue(value_text.to_string()))?;
+
+        Ok(Self {
+            register,
+            comparison,
+            value,
+        })
+    }
+}
+
+fn parse_value(text: &str) -> Option<u16> {
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = text.strip_suffix('h').or_else(|| text.strip_suffix('H')) {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+/// Execute breakpoints and register conditions checked by the executor after every instruction.
+/// Memory and I/O watchpoints live on `Bus` and `IoBus`, which see the accesses.
+pub struct Breakpoints {
+    /// Breakpoint addresses with an optional condition that has to hold as well
+    breakpoints: BTreeMap<u16, Option<Condition>>,
+    conditions: Vec<Condition>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: BTreeMap::new(),
+            conditions: Vec::new(),
+        }
+    }
+
+    /// Stops before the instruction at `address` is executed
+    pub fn add(&mut self, address: u16) {
+        self.breakpoints.insert(address, None);
+    }
+
+    /// Stops before the instruction at `address` if `condition` holds at that point
+    pub fn add_conditional(&mut self, address: u16, condition: Condition) {
+        self.breakpoints.insert(address, Some(condition));
+    }
+
+    pub fn remove(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address).is_some()
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (u16, Option<Condition>)> + '_ {
+        self.breakpoints
+            .iter()
+            .map(|(address, condition)| (*address, *condition))
+    }
+
+    /// Stops wherever `condition` holds after an instruction
+    pub fn add_condition(&mut self, condition: Condition) {
+        if !self.conditions.contains(&condition) {
+            self.conditions.push(condition);
+        }
+    }
+
+    pub fn remove_condition(&mut self, condition: &Condition) -> bool {
+        let count = self.conditions.len();
+        self.conditions.retain(|c| c != condition);
+        self.conditions.len() != count
+    }
+
+    pub fn conditions(&self) -> &[Condition] {
+        &self.conditions
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.conditions.clear();
+    }
+
+    /// Returns the breakpoint address if the CPU is about to execute a breakpoint
+    pub fn breakpoint_hit(&self, cpu: &CPU) -> Option<u16> {
+        match self.breakpoints.get(&cpu.pc) {
+            Some(None) => Some(cpu.pc),
+            Some(Some(condition)) if condition.holds(cpu) => Some(cpu.pc),
+            _ => None,
+        }
+    }
+
+    pub fn condition_hit(&self, cpu: &CPU) -> Option<Condition> {
+        self.conditions.iter().copied().find(|c| c.holds(cpu))
+    }
+}
+
+impl Default for Breakpoints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::executor::*;
+    use super::*;
+
+    fn condition(text: &str) -> Condition {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn watchpoints_match_their_access_and_range() {
+        let mut list = WatchList::new();
+        list.add(Watchpoint::new(0x10..=0x1F, Access::Read));
+        list.add(Watchpoint::new(0x20..=0x20, Access::Write));
+        list.add(Watchpoint::new(0x30..=0x30, Access::ReadWrite));
+
+        let hit = |list: &mut WatchList, address, access| {
+            list.check(address, 0x55, access);
+            list.take_hit()
+        };
+        assert!(hit(&mut list, 0x10, Access::Read).is_some());
+        assert!(hit(&mut list, 0x1F, Access::Read).is_some());
+        assert!(hit(&mut list, 0x10, Access::Write).is_none());
+        assert!(hit(&mut list, 0x20, Access::Read).is_none());
+        assert_eq!(
+            hit(&mut list, 0x20, Access::Write),
+            Some(WatchHit {
+                address: 0x20,
+                value: 0x55,
+                access: Access::Write
+            })
+        );
+        assert!(hit(&mut list, 0x30, Access::Read).is_some());
+        assert!(hit(&mut list, 0x30, Access::Write).is_some());
+        assert!(hit(&mut list, 0x21, Access::Write).is_none());
+    }
+
+    #[test]
+    fn first_hit_is_latched_until_taken() {
+        let mut list = WatchList::new();
+        list.add(Watchpoint::new(0x10..=0x11, Access::ReadWrite));
+        list.check(0x10, 1, Access::Read);
+        list.check(0x11, 2, Access::Write);
+        assert_eq!(
+            list.take_hit(),
+            Some(WatchHit {
+                address: 0x10,
+                value: 1,
+                access: Access::Read
+            })
+        );
+        assert_eq!(list.take_hit(), None);
+    }
+
+    #[test]
+    fn executor_only_reports_hits_of_the_last_instruction() {
+        let mut cpu = CPU::new();
+        // STA 0040h; LDA 0040h; OUT 10h; IN 10h
+        cpu.bus
+            .load_bytes(
+                0,
+                &[0x32, 0x40, 0x00, 0x3A, 0x40, 0x00, 0xD3, 0x10, 0xDB, 0x10],
+            )
+            .unwrap();
+        cpu.bus
+            .watchpoints()
+            .add(Watchpoint::new(0x0040..=0x0040, Access::Read));
+        cpu.io
+            .watchpoints()
+            .add(Watchpoint::new(0x10..=0x10, Access::Read));
+        // A host access before the run is not the program's doing
+        cpu.bus.read_byte(0x0040);
+
+        let mut executor = Executor::new(&mut cpu);
+        assert_eq!(executor.run_instructions(1), StopReason::BudgetExhausted);
+        assert!(matches!(
+            executor.run_instructions(1),
+            StopReason::Watchpoint(WatchHit {
+                address: 0x0040,
+                access: Access::Read,
+                ..
+            })
+        ));
+        assert_eq!(executor.run_instructions(1), StopReason::BudgetExhausted);
+        assert!(matches!(
+            executor.run_instructions(1),
+            StopReason::PortWatchpoint(WatchHit {
+                address: 0x10,
+                access: Access::Read,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn conditions_parse_operators_and_values() {
+        let parse = |text: &str| {
+            let condition = condition(text);
+            (condition.register, condition.comparison, condition.value)
+        };
+        assert_eq!(parse("A<=5"), (RegisterName::A, Comparison::LessOrEqual, 5));
+        assert_eq!(parse("A<5"), (RegisterName::A, Comparison::Less, 5));
+        assert_eq!(
+            parse("a >= 10"),
+            (RegisterName::A, Comparison::GreaterOrEqual, 10)
+        );
+        assert_eq!(
+            parse("HL == 0x2400"),
+            (RegisterName::HL, Comparison::Equal, 0x2400)
+        );
+        assert_eq!(
+            parse("hl != 2400h"),
+            (RegisterName::HL, Comparison::NotEqual, 0x2400)
+        );
+        assert_eq!(
+            parse("SP > 0FFH"),
+            (RegisterName::SP, Comparison::Greater, 0xFF)
+        );
+        assert_eq!(condition("HL == 0x2400").to_string(), "HL == 2400h");
+    }
+
+    #[test]
+    fn conditions_reject_bad_input() {
+        let error = |text: &str| text.parse::<Condition>().unwrap_err();
+        assert_eq!(
+            error("B == 256"),
+            ConditionError::InvalidValue(String::from("256"))
+        );
+        assert_eq!(
+            error("C == 0x100"),
+            ConditionError::InvalidValue(String::from("0x100"))
+        );
+        assert_eq!(condition("BC == 256").value, 256);
+        assert_eq!(
+            error("Q == 1"),
+            ConditionError::UnknownRegister(String::from("Q"))
+        );
+        assert_eq!(error("A 5"), ConditionError::MissingOperator);
+        assert_eq!(
+            error("A == 12x"),
+            ConditionError::InvalidValue(String::from("12x"))
+        );
+    }
+
+    #[test]
+    fn conditional_breakpoint_needs_its_condition() {
+        let mut breakpoints = Breakpoints::new();
+        breakpoints.add_conditional(0x0002, condition("A == 1"));
+        breakpoints.add(0x0005);
+
+        let mut cpu = CPU::new();
+        cpu.pc = 0x0002;
+        assert_eq!(breakpoints.breakpoint_hit(&cpu), None);
+        cpu.a = 1;
+        assert_eq!(breakpoints.breakpoint_hit(&cpu), Some(0x0002));
+        cpu.pc = 0x0003;
+        assert_eq!(breakpoints.breakpoint_hit(&cpu), None);
+        cpu.pc = 0x0005;
+        assert_eq!(breakpoints.breakpoint_hit(&cpu), Some(0x0005));
+    }
+}
@@ -1,36 +1,171 @@
+use super::breakpoint::*;
+use super::history::*;
+use super::memory::*;
+use super::trace::*;
+
+use std::error;
+use std::fmt;
+use std::rc::Rc;
+
+pub const MEMORY_SIZE: usize = 0x10000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BusError {
+    Overflow { address: u16, length: usize },
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BusError::Overflow { address, length } => write!(
+                f,
+                "{} bytes at {:04X}h run past the end of the address space",
+                length, address
+            ),
+        }
+    }
+}
+
+impl error::Error for BusError {}
+
 pub struct Bus {
-    memory: [u8; 0xffff],
+    memory: Box<dyn Memory>,
+    tracer: Rc<dyn Tracer>,
+    watchpoints: WatchList,
+    journal: Option<Vec<MemoryWrite>>,
 }
 
 impl Bus {
     pub fn new() -> Self {
+        Self::with_memory(Box::new(Ram::new()))
+    }
+
+    pub fn with_memory(memory: Box<dyn Memory>) -> Self {
         Self {
-            memory: [0; 0xffff],
+            memory,
+            tracer: Rc::new(NullTracer),
+            watchpoints: WatchList::new(),
+            journal: None,
         }
     }
 
-    pub fn load_bytes(&mut self, starting_address: u16, data: &[u8]) {
-        for i in 0..data.len() {
-            let idx = starting_address as usize + i;
-            self.memory[idx] = data[i];
+    pub fn set_tracer(&mut self, tracer: Rc<dyn Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Memory watchpoints, checked by `read_byte` and `write_byte`
+    pub fn watchpoints(&mut self) -> &mut WatchList {
+        &mut self.watchpoints
+    }
+
+    /// Copies `data` to memory starting at `starting_address`.
+    /// Nothing is written if the data does not fit at or below FFFFh.
+    pub fn load_bytes(&mut self, starting_address: u16, data: &[u8]) -> Result<(), BusError> {
+        let start = starting_address as usize;
+        if data.len() > MEMORY_SIZE - start {
+            return Err(BusError::Overflow {
+                address: starting_address,
+                length: data.len(),
+            });
+        }
+
+        for (i, byte) in data.iter().enumerate() {
+            self.memory.poke((start + i) as u16, *byte);
         }
+        Ok(())
     }
 
     pub fn read_byte(&mut self, address: u16) -> u8 {
-        let result = *self.get_mapped_location(address);
-        println!("[BUS]: Reading {:02X}h from {:04X}h", result, address);
+        let result = self.memory.read(address);
+        self.tracer.trace(&TraceEvent::BusRead {
+            address,
+            value: result,
+        });
+        self.watchpoints.check(address, result, Access::Read);
         result
     }
 
-    pub fn write_byte(&mut self, address: u16, value: u8) {
-        println!("[BUS]: Writing {:02X}h to {:04X}h", value, address);
-        *self.get_mapped_location(address) = value;
+    /// Reads a byte without any side effects, used by the decoder and debugging tools
+    pub fn peek_byte(&self, address: u16) -> u8 {
+        self.memory.peek(address)
     }
 
-    fn get_mapped_location(&mut self, address: u16) -> &mut u8 {
-        // Map custom memory regions here
-        match address {
-            _ => &mut self.memory[address as usize],
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        self.tracer.trace(&TraceEvent::BusWrite { address, value });
+        self.watchpoints.check(address, value, Access::Write);
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push(MemoryWrite {
+                address,
+                old: self.memory.peek(address),
+                new: value,
+            });
         }
+        self.memory.write(address, value);
+    }
+
+    /// Writes a byte without any side effects, also into ROM
+    pub fn poke_byte(&mut self, address: u16, value: u8) {
+        self.memory.poke(address, value);
+    }
+
+    /// Starts recording every `write_byte` until `take_journal` is called
+    pub fn start_journal(&mut self) {
+        self.journal = Some(Vec::new());
+    }
+
+    pub fn take_journal(&mut self) -> Vec<MemoryWrite> {
+        self.journal.take().unwrap_or_default()
+    }
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn top_of_memory_is_addressable() {
+        let mut bus = Bus::new();
+        bus.write_byte(0xFFFF, 0x42);
+        assert_eq!(bus.read_byte(0xFFFF), 0x42);
+        assert_eq!(bus.peek_byte(0xFFFF), 0x42);
+        assert_eq!(bus.peek_byte(0x0000), 0x00);
+    }
+
+    #[test]
+    fn load_bytes_fits_exactly_up_to_ffff() {
+        let mut bus = Bus::new();
+        bus.load_bytes(0xFFFD, &[0x01, 0x02, 0x03]).unwrap();
+        assert_eq!(bus.peek_byte(0xFFFD), 0x01);
+        assert_eq!(bus.peek_byte(0xFFFF), 0x03);
+        assert_eq!(bus.peek_byte(0x0000), 0x00);
+
+        let image = vec![0xAA; MEMORY_SIZE];
+        bus.load_bytes(0x0000, &image).unwrap();
+        assert_eq!(bus.peek_byte(0xFFFF), 0xAA);
+    }
+
+    #[test]
+    fn load_bytes_rejects_overflow_by_one() {
+        let mut bus = Bus::new();
+        assert_eq!(
+            bus.load_bytes(0xFFFD, &[0x01, 0x02, 0x03, 0x04]),
+            Err(BusError::Overflow {
+                address: 0xFFFD,
+                length: 4
+            })
+        );
+        // Nothing is written on failure, not even the bytes that would fit
+        assert_eq!(bus.peek_byte(0xFFFD), 0x00);
+        assert_eq!(bus.peek_byte(0x0000), 0x00);
+
+        let image = vec![0xAA; MEMORY_SIZE + 1];
+        assert!(bus.load_bytes(0x0000, &image).is_err());
+        assert_eq!(bus.peek_byte(0x0000), 0x00);
     }
 }
@@ -0,0 +1,89 @@
+use super::bus::*;
+use super::cpu::*;
+use super::executor::*;
+
+use std::io::{self, Write};
+
+/// .COM programs are loaded to and started at the beginning of the transient program area
+pub const TPA_START: u16 = 0x0100;
+/// Programs call the BDOS through the jump at 0005h
+pub const BDOS_ENTRY: u16 = 0x0005;
+/// Jumping to 0000h reboots CP/M, which ends the program
+pub const WARM_BOOT: u16 = 0x0000;
+/// Where the jump at 0005h leads. Programs read the word at 0006h as top of memory.
+pub const BDOS_ADDRESS: u16 = 0xFE00;
+/// BIOS warm boot routine the jump at 0000h leads to
+const BIOS_WARM_BOOT: u16 = 0xFF03;
+
+const JMP: u8 = 0xC3;
+const RET: u8 = 0xC9;
+const HLT: u8 = 0x76;
+
+const SYSTEM_RESET: u8 = 0;
+const CONSOLE_OUTPUT: u8 = 2;
+const PRINT_STRING: u8 = 9;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CpmExit {
+    /// The program jumped to 0000h, returned to the CCP or called BDOS function 0
+    WarmBoot,
+    /// The executor stopped for another reason, e.g. a HLT or an error
+    Stopped(StopReason),
+}
+
+/// Loads a .COM program to 0100h and sets up the zero page and a minimal BDOS.
+/// The program starts at 0100h with a return address of 0000h on the stack,
+/// so a final RET ends the program like on a real CP/M system.
+pub fn load_com(cpu: &mut CPU, program: &[u8]) -> Result<(), BusError> {
+    cpu.bus.load_bytes(TPA_START, program)?;
+
+    let [bios_low, bios_high] = BIOS_WARM_BOOT.to_le_bytes();
+    let [bdos_low, bdos_high] = BDOS_ADDRESS.to_le_bytes();
+    cpu.bus.load_bytes(WARM_BOOT, &[JMP, bios_low, bios_high])?;
+    cpu.bus
+        .load_bytes(BDOS_ENTRY, &[JMP, bdos_low, bdos_high])?;
+    // Calls are trapped at the entry point, the RET only returns to the caller
+    cpu.bus.load_bytes(BDOS_ADDRESS, &[RET])?;
+    cpu.bus.load_bytes(BIOS_WARM_BOOT, &[HLT])?;
+
+    cpu.pc = TPA_START;
+    cpu.sp = BDOS_ADDRESS;
+    cpu.push(WARM_BOOT);
+    Ok(())
+}
+
+/// Runs a program set up by `load_com` and services console output through `console`.
+/// BDOS function 2 prints the character in E, function 9 the `$` terminated string at DE.
+/// Other BDOS functions are ignored.
+pub fn run_com<W: Write>(executor: &mut Executor, console: &mut W) -> io::Result<CpmExit> {
+    loop {
+        let reason = executor.run_until(|cpu| cpu.pc == WARM_BOOT || cpu.pc == BDOS_ENTRY);
+        if reason != StopReason::ConditionMet {
+            return Ok(CpmExit::Stopped(reason));
+        }
+
+        let cpu = executor.cpu();
+        if cpu.pc == WARM_BOOT {
+            return Ok(CpmExit::WarmBoot);
+        }
+
+        match cpu.c {
+            SYSTEM_RESET => return Ok(CpmExit::WarmBoot),
+            CONSOLE_OUTPUT => console.write_all(&[cpu.e])?,
+            PRINT_STRING => {
+                let mut address = cpu.get_de();
+                let mut text = Vec::new();
+                for _ in 0..MEMORY_SIZE {
+                    let byte = cpu.bus.peek_byte(address);
+                    if byte == b'$' {
+                        break;
+                    }
+                    text.push(byte);
+                    address = address.wrapping_add(1);
+                }
+                console.write_all(&text)?;
+            }
+            _ => {}
+        }
+    }
+}
@@ -1,12 +1,21 @@
 use super::bus::*;
+use super::io::*;
+use super::trace::*;
 use super::util::*;
 
+use std::rc::Rc;
+
 pub const CARRY_FLAG: usize = 0;
 pub const PARITY_FLAG: usize = 2;
 pub const AUX_CARRY_FLAG: usize = 4;
 pub const ZERO_FLAG: usize = 6;
 pub const SIGN_FLAG: usize = 7;
 
+// Bits 1, 3 and 5 of the flags byte are unused and always read as 1, 0 and 0
+const FLAGS_FIXED_SET: u8 = 0x02;
+const FLAGS_FIXED_CLEAR: u8 = 0x28;
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
     pub a: u8,
     pub flags: u8,
@@ -18,14 +27,26 @@ pub struct CPU {
     pub l: u8,
     pub sp: u16,
     pub pc: u16,
+    /// Interrupt enable flip-flop, set by EI and cleared by DI or an accepted interrupt
+    pub inte: bool,
+    /// Set for the one instruction after EI during which interrupts are still held off
+    pub interrupt_delay: bool,
+    /// Set by HLT, the CPU stops fetching until an interrupt is accepted
+    pub halted: bool,
     pub bus: Bus,
+    pub io: IoBus,
+    tracer: Rc<dyn Tracer>,
 }
 
 impl CPU {
     pub fn new() -> Self {
+        Self::with_bus(Bus::new())
+    }
+
+    pub fn with_bus(bus: Bus) -> Self {
         Self {
             a: 0,
-            flags: 0,
+            flags: FLAGS_FIXED_SET,
             b: 0,
             c: 0,
             d: 0,
@@ -34,36 +55,44 @@ impl CPU {
             l: 0,
             sp: 0,
             pc: 0,
-            bus: Bus::new(),
+            inte: false,
+            interrupt_delay: false,
+            halted: false,
+            bus,
+            io: IoBus::new(),
+            tracer: Rc::new(NullTracer),
         }
     }
 
-    pub fn read_byte(&mut self) -> u8 {
-        let ret = self.bus.read_byte(self.pc);
-        self.pc += 1;
-        ret
+    /// Routes trace events of the CPU, its bus and its I/O bus to `tracer`
+    pub fn set_tracer(&mut self, tracer: Rc<dyn Tracer>) {
+        self.bus.set_tracer(tracer.clone());
+        self.io.set_tracer(tracer.clone());
+        self.tracer = tracer;
     }
 
-    pub fn read_word(&mut self) -> u16 {
-        let low = self.read_byte();
-        let high = self.read_byte();
-        join_bytes(high, low)
+    pub fn tracer(&self) -> &dyn Tracer {
+        self.tracer.as_ref()
     }
 
     pub fn jump(&mut self, address: u16) {
         self.pc = address;
     }
 
+    /// Pushes a word the way the 8080 does: the stack grows downwards,
+    /// the high byte ends up at SP-1 and the low byte at SP-2.
     pub fn push(&mut self, value: u16) {
+        self.sp = self.sp.wrapping_sub(1);
+        self.bus.write_byte(self.sp, get_high_byte(value));
+        self.sp = self.sp.wrapping_sub(1);
         self.bus.write_byte(self.sp, get_low_byte(value));
-        self.bus.write_byte(self.sp + 1, get_high_byte(value));
-        self.sp += 2;
     }
 
     pub fn pop(&mut self) -> u16 {
-        let high = self.bus.read_byte(self.sp - 1);
-        let low = self.bus.read_byte(self.sp - 2);
-        self.sp -= 2;
+        let low = self.bus.read_byte(self.sp);
+        self.sp = self.sp.wrapping_add(1);
+        let high = self.bus.read_byte(self.sp);
+        self.sp = self.sp.wrapping_add(1);
 
         join_bytes(high, low)
     }
@@ -80,8 +109,12 @@ impl CPU {
         get_bit(self.flags, flag)
     }
 
+    pub fn set_flags(&mut self, value: u8) {
+        self.flags = (value | FLAGS_FIXED_SET) & !FLAGS_FIXED_CLEAR;
+    }
+
     pub fn get_psw(&self) -> u16 {
-        join_bytes(self.a, self.flags)
+        join_bytes(self.a, (self.flags | FLAGS_FIXED_SET) & !FLAGS_FIXED_CLEAR)
     }
 
     pub fn get_bc(&self) -> u16 {
@@ -98,7 +131,7 @@ impl CPU {
 
     pub fn set_psw(&mut self, value: u16) {
         self.a = get_high_byte(value);
-        self.flags = get_low_byte(value);
+        self.set_flags(get_low_byte(value));
     }
 
     pub fn set_bc(&mut self, value: u16) {
@@ -116,3 +149,142 @@ impl CPU {
         self.l = get_low_byte(value);
     }
 }
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::executor::*;
+    use super::*;
+
+    fn window(cpu: &CPU, start: u16) -> [u8; 4] {
+        let mut bytes = [0; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = cpu.bus.peek_byte(start.wrapping_add(i as u16));
+        }
+        bytes
+    }
+
+    /// Runs `program` from 0100h one instruction at a time and compares SP and
+    /// the four bytes at `start` against `expected` after every instruction
+    fn check_stack(program: &[u8], start: u16, expected: &[(u16, [u8; 4])]) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.bus.load_bytes(0x0100, program).unwrap();
+        cpu.pc = 0x0100;
+        let mut executor = Executor::new(&mut cpu);
+        for (step, (sp, bytes)) in expected.iter().enumerate() {
+            executor.execute().unwrap();
+            let cpu = executor.cpu();
+            assert_eq!(cpu.sp, *sp, "SP after instruction {}", step + 1);
+            assert_eq!(
+                window(cpu, start),
+                *bytes,
+                "stack after instruction {}",
+                step + 1
+            );
+        }
+        assert!(executor.is_halted());
+        cpu
+    }
+
+    #[test]
+    fn push_wraps_below_zero() {
+        let mut cpu = CPU::new();
+        cpu.sp = 0x0000;
+        cpu.push(0x1234);
+        assert_eq!(cpu.sp, 0xFFFE);
+        assert_eq!(window(&cpu, 0xFFFE), [0x34, 0x12, 0x00, 0x00]);
+
+        let mut cpu = CPU::new();
+        cpu.sp = 0x0001;
+        cpu.push(0x1234);
+        assert_eq!(cpu.sp, 0xFFFF);
+        assert_eq!(window(&cpu, 0xFFFE), [0x00, 0x34, 0x12, 0x00]);
+    }
+
+    #[test]
+    fn pop_wraps_above_ffff() {
+        let mut cpu = CPU::new();
+        cpu.bus.load_bytes(0xFFFE, &[0x34, 0x12]).unwrap();
+        cpu.sp = 0xFFFE;
+        assert_eq!(cpu.pop(), 0x1234);
+        assert_eq!(cpu.sp, 0x0000);
+
+        let mut cpu = CPU::new();
+        cpu.bus.poke_byte(0xFFFF, 0x34);
+        cpu.bus.poke_byte(0x0000, 0x12);
+        cpu.sp = 0xFFFF;
+        assert_eq!(cpu.pop(), 0x1234);
+        assert_eq!(cpu.sp, 0x0001);
+    }
+
+    #[test]
+    fn stack_starting_at_0000() {
+        #[rustfmt::skip]
+        let program = [
+            0x31, 0x00, 0x00, // 0100 LXI SP,0000h
+            0x01, 0x34, 0x12, // 0103 LXI B,1234h
+            0xC5,             // 0106 PUSH B
+            0x21, 0x78, 0x56, // 0107 LXI H,5678h
+            0xE3,             // 010A XTHL
+            0xCD, 0x10, 0x01, // 010B CALL 0110h
+            0xD1,             // 010E POP D
+            0x76,             // 010F HLT
+            0xC9,             // 0110 RET
+        ];
+        let cpu = check_stack(
+            &program,
+            0xFFFC,
+            &[
+                (0x0000, [0x00, 0x00, 0x00, 0x00]),
+                (0x0000, [0x00, 0x00, 0x00, 0x00]),
+                (0xFFFE, [0x00, 0x00, 0x34, 0x12]),
+                (0xFFFE, [0x00, 0x00, 0x34, 0x12]),
+                (0xFFFE, [0x00, 0x00, 0x78, 0x56]),
+                (0xFFFC, [0x0E, 0x01, 0x78, 0x56]),
+                (0xFFFE, [0x0E, 0x01, 0x78, 0x56]),
+                (0x0000, [0x0E, 0x01, 0x78, 0x56]),
+                (0x0000, [0x0E, 0x01, 0x78, 0x56]),
+            ],
+        );
+        assert_eq!(cpu.get_hl(), 0x1234);
+        assert_eq!(cpu.get_de(), 0x5678);
+    }
+
+    #[test]
+    fn stack_across_ffff() {
+        #[rustfmt::skip]
+        let program = [
+            0x31, 0x01, 0x00, // 0100 LXI SP,0001h
+            0xCD, 0x10, 0x01, // 0103 CALL 0110h
+            0x31, 0xFF, 0xFF, // 0106 LXI SP,0FFFFh
+            0x21, 0xCD, 0xAB, // 0109 LXI H,0ABCDh
+            0xE3,             // 010C XTHL
+            0xC1,             // 010D POP B
+            0x76,             // 010E HLT
+            0x00,             // 010F
+            0xC9,             // 0110 RET
+        ];
+        // The window covers FFFEh to 0001h
+        let cpu = check_stack(
+            &program,
+            0xFFFE,
+            &[
+                (0x0001, [0x00, 0x00, 0x00, 0x00]),
+                (0xFFFF, [0x00, 0x06, 0x01, 0x00]),
+                (0x0001, [0x00, 0x06, 0x01, 0x00]),
+                (0xFFFF, [0x00, 0x06, 0x01, 0x00]),
+                (0xFFFF, [0x00, 0x06, 0x01, 0x00]),
+                (0xFFFF, [0x00, 0xCD, 0xAB, 0x00]),
+                (0x0001, [0x00, 0xCD, 0xAB, 0x00]),
+                (0x0001, [0x00, 0xCD, 0xAB, 0x00]),
+            ],
+        );
+        assert_eq!(cpu.get_hl(), 0x0106);
+        assert_eq!(cpu.get_bc(), 0xABCD);
+    }
+}
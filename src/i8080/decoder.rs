@@ -1,4 +1,8 @@
-use super::cpu::*;
+use super::bus::*;
+use super::util::*;
+
+use std::error;
+use std::fmt;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Register {
@@ -96,17 +100,345 @@ pub enum Instruction {
     Cpi(u8),
 }
 
-pub fn decode_instruction(opcode: u8, cpu: &mut CPU) -> Instruction {
-    let result = match opcode {
+impl Instruction {
+    /// Number of bytes the instruction occupies including its operand
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::Lxi(_, _)
+            | Instruction::Shld(_)
+            | Instruction::Sta(_)
+            | Instruction::Lhld(_)
+            | Instruction::Lda(_)
+            | Instruction::Jnz(_)
+            | Instruction::Jnc(_)
+            | Instruction::Jpo(_)
+            | Instruction::Jp(_)
+            | Instruction::Jmp(_)
+            | Instruction::Cnz(_)
+            | Instruction::Cnc(_)
+            | Instruction::Cpo(_)
+            | Instruction::Cp(_)
+            | Instruction::Jz(_)
+            | Instruction::Jc(_)
+            | Instruction::Jpe(_)
+            | Instruction::Jm(_)
+            | Instruction::Cz(_)
+            | Instruction::Cc(_)
+            | Instruction::Cpe(_)
+            | Instruction::Cm(_)
+            | Instruction::Call(_) => 3,
+            Instruction::Mvi(_, _)
+            | Instruction::Out(_)
+            | Instruction::Adi(_)
+            | Instruction::Sui(_)
+            | Instruction::Ani(_)
+            | Instruction::Ori(_)
+            | Instruction::In(_)
+            | Instruction::Aci(_)
+            | Instruction::Sbi(_)
+            | Instruction::Xri(_)
+            | Instruction::Cpi(_) => 2,
+            _ => 1,
+        }
+    }
+
+    /// Replaces the immediate operand of the instruction, byte operands use the low byte
+    pub fn with_operand(self, operand: u16) -> Instruction {
+        let byte = operand as u8;
+        match self {
+            Instruction::Lxi(reg, _) => Instruction::Lxi(reg, operand),
+            Instruction::Shld(_) => Instruction::Shld(operand),
+            Instruction::Sta(_) => Instruction::Sta(operand),
+            Instruction::Lhld(_) => Instruction::Lhld(operand),
+            Instruction::Lda(_) => Instruction::Lda(operand),
+            Instruction::Jnz(_) => Instruction::Jnz(operand),
+            Instruction::Jnc(_) => Instruction::Jnc(operand),
+            Instruction::Jpo(_) => Instruction::Jpo(operand),
+            Instruction::Jp(_) => Instruction::Jp(operand),
+            Instruction::Jmp(_) => Instruction::Jmp(operand),
+            Instruction::Cnz(_) => Instruction::Cnz(operand),
+            Instruction::Cnc(_) => Instruction::Cnc(operand),
+            Instruction::Cpo(_) => Instruction::Cpo(operand),
+            Instruction::Cp(_) => Instruction::Cp(operand),
+            Instruction::Jz(_) => Instruction::Jz(operand),
+            Instruction::Jc(_) => Instruction::Jc(operand),
+            Instruction::Jpe(_) => Instruction::Jpe(operand),
+            Instruction::Jm(_) => Instruction::Jm(operand),
+            Instruction::Cz(_) => Instruction::Cz(operand),
+            Instruction::Cc(_) => Instruction::Cc(operand),
+            Instruction::Cpe(_) => Instruction::Cpe(operand),
+            Instruction::Cm(_) => Instruction::Cm(operand),
+            Instruction::Call(_) => Instruction::Call(operand),
+            Instruction::Mvi(reg, _) => Instruction::Mvi(reg, byte),
+            Instruction::Out(_) => Instruction::Out(byte),
+            Instruction::Adi(_) => Instruction::Adi(byte),
+            Instruction::Sui(_) => Instruction::Sui(byte),
+            Instruction::Ani(_) => Instruction::Ani(byte),
+            Instruction::Ori(_) => Instruction::Ori(byte),
+            Instruction::In(_) => Instruction::In(byte),
+            Instruction::Aci(_) => Instruction::Aci(byte),
+            Instruction::Sbi(_) => Instruction::Sbi(byte),
+            Instruction::Xri(_) => Instruction::Xri(byte),
+            Instruction::Cpi(_) => Instruction::Cpi(byte),
+            _ => self,
+        }
+    }
+
+    /// The immediate operand, zero for instructions without one
+    pub fn operand(&self) -> u16 {
+        match *self {
+            Instruction::Lxi(_, value)
+            | Instruction::Shld(value)
+            | Instruction::Sta(value)
+            | Instruction::Lhld(value)
+            | Instruction::Lda(value)
+            | Instruction::Jnz(value)
+            | Instruction::Jnc(value)
+            | Instruction::Jpo(value)
+            | Instruction::Jp(value)
+            | Instruction::Jmp(value)
+            | Instruction::Cnz(value)
+            | Instruction::Cnc(value)
+            | Instruction::Cpo(value)
+            | Instruction::Cp(value)
+            | Instruction::Jz(value)
+            | Instruction::Jc(value)
+            | Instruction::Jpe(value)
+            | Instruction::Jm(value)
+            | Instruction::Cz(value)
+            | Instruction::Cc(value)
+            | Instruction::Cpe(value)
+            | Instruction::Cm(value)
+            | Instruction::Call(value) => value,
+            Instruction::Mvi(_, value)
+            | Instruction::Out(value)
+            | Instruction::Adi(value)
+            | Instruction::Sui(value)
+            | Instruction::Ani(value)
+            | Instruction::Ori(value)
+            | Instruction::In(value)
+            | Instruction::Aci(value)
+            | Instruction::Sbi(value)
+            | Instruction::Xri(value)
+            | Instruction::Cpi(value) => value as u16,
+            _ => 0,
+        }
+    }
+
+    /// The register operands in source order, e.g. `[B, C]` for `MOV B,C`
+    pub fn registers(&self) -> Vec<Register> {
+        match *self {
+            Instruction::Mov(dst, src) => vec![dst, src],
+            Instruction::Lxi(reg, _)
+            | Instruction::Mvi(reg, _)
+            | Instruction::Stax(reg)
+            | Instruction::Inx(reg)
+            | Instruction::Inr(reg)
+            | Instruction::Dcr(reg)
+            | Instruction::Dad(reg)
+            | Instruction::Ldax(reg)
+            | Instruction::Dcx(reg)
+            | Instruction::Add(reg)
+            | Instruction::Adc(reg)
+            | Instruction::Sub(reg)
+            | Instruction::Sbb(reg)
+            | Instruction::Ana(reg)
+            | Instruction::Xra(reg)
+            | Instruction::Ora(reg)
+            | Instruction::Cmp(reg)
+            | Instruction::Pop(reg)
+            | Instruction::Push(reg) => vec![reg],
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::Nop => "NOP",
+            Instruction::Lxi(_, _) => "LXI",
+            Instruction::Stax(_) => "STAX",
+            Instruction::Shld(_) => "SHLD",
+            Instruction::Sta(_) => "STA",
+            Instruction::Inx(_) => "INX",
+            Instruction::Inr(_) => "INR",
+            Instruction::Dcr(_) => "DCR",
+            Instruction::Mvi(_, _) => "MVI",
+            Instruction::Rlc => "RLC",
+            Instruction::Ral => "RAL",
+            Instruction::Daa => "DAA",
+            Instruction::Stc => "STC",
+            Instruction::Dad(_) => "DAD",
+            Instruction::Ldax(_) => "LDAX",
+            Instruction::Lhld(_) => "LHLD",
+            Instruction::Lda(_) => "LDA",
+            Instruction::Dcx(_) => "DCX",
+            Instruction::Rrc => "RRC",
+            Instruction::Rar => "RAR",
+            Instruction::Cma => "CMA",
+            Instruction::Cmc => "CMC",
+            Instruction::Mov(_, _) => "MOV",
+            Instruction::Hlt => "HLT",
+            Instruction::Add(_) => "ADD",
+            Instruction::Adc(_) => "ADC",
+            Instruction::Sub(_) => "SUB",
+            Instruction::Sbb(_) => "SBB",
+            Instruction::Ana(_) => "ANA",
+            Instruction::Xra(_) => "XRA",
+            Instruction::Ora(_) => "ORA",
+            Instruction::Cmp(_) => "CMP",
+            Instruction::Rnz => "RNZ",
+            Instruction::Rnc => "RNC",
+            Instruction::Rpo => "RPO",
+            Instruction::Rp => "RP",
+            Instruction::Pop(_) => "POP",
+            Instruction::Jnz(_) => "JNZ",
+            Instruction::Jnc(_) => "JNC",
+            Instruction::Jpo(_) => "JPO",
+            Instruction::Jp(_) => "JP",
+            Instruction::Jmp(_) => "JMP",
+            Instruction::Out(_) => "OUT",
+            Instruction::Xthl => "XTHL",
+            Instruction::Di => "DI",
+            Instruction::Cnz(_) => "CNZ",
+            Instruction::Cnc(_) => "CNC",
+            Instruction::Cpo(_) => "CPO",
+            Instruction::Cp(_) => "CP",
+            Instruction::Push(_) => "PUSH",
+            Instruction::Adi(_) => "ADI",
+            Instruction::Sui(_) => "SUI",
+            Instruction::Ani(_) => "ANI",
+            Instruction::Ori(_) => "ORI",
+            Instruction::Rst(_) => "RST",
+            Instruction::Rz => "RZ",
+            Instruction::Rc => "RC",
+            Instruction::Rpe => "RPE",
+            Instruction::Rm => "RM",
+            Instruction::Ret => "RET",
+            Instruction::Pchl => "PCHL",
+            Instruction::Sphl => "SPHL",
+            Instruction::Jz(_) => "JZ",
+            Instruction::Jc(_) => "JC",
+            Instruction::Jpe(_) => "JPE",
+            Instruction::Jm(_) => "JM",
+            Instruction::In(_) => "IN",
+            Instruction::Xchg => "XCHG",
+            Instruction::Ei => "EI",
+            Instruction::Cz(_) => "CZ",
+            Instruction::Cc(_) => "CC",
+            Instruction::Cpe(_) => "CPE",
+            Instruction::Cm(_) => "CM",
+            Instruction::Call(_) => "CALL",
+            Instruction::Aci(_) => "ACI",
+            Instruction::Sbi(_) => "SBI",
+            Instruction::Xri(_) => "XRI",
+            Instruction::Cpi(_) => "CPI",
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Register::A => "A",
+            Register::Flags => "F",
+            Register::B => "B",
+            Register::C => "C",
+            Register::D => "D",
+            Register::E => "E",
+            Register::H => "H",
+            Register::L => "L",
+            Register::SP => "SP",
+            Register::M => "M",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Every opcode mapped to its instruction with a zeroed operand.
+/// Decoding looks the opcode up here and only fills in the immediate bytes,
+/// so there is nothing to cache and nothing that can go stale.
+pub const OPCODE_TABLE: [Instruction; 256] = build_opcode_table();
+
+const fn build_opcode_table() -> [Instruction; 256] {
+    let mut table = [Instruction::Nop; 256];
+    let mut opcode = 0;
+    while opcode < 256 {
+        table[opcode] = template(opcode as u8);
+        opcode += 1;
+    }
+    table
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    Empty,
+    Truncated { needed: usize, available: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Empty => write!(f, "no bytes to decode"),
+            DecodeError::Truncated { needed, available } => write!(
+                f,
+                "instruction needs {} bytes but only {} are available",
+                needed, available
+            ),
+        }
+    }
+}
+
+impl error::Error for DecodeError {}
+
+/// Decodes the instruction at the start of `bytes` and returns it together with its size.
+/// The decoder only looks at the slice, so it can be used for disassembly and lookahead.
+pub fn decode(bytes: &[u8]) -> Result<(Instruction, usize), DecodeError> {
+    let opcode = *bytes.first().ok_or(DecodeError::Empty)?;
+    let template = OPCODE_TABLE[opcode as usize];
+    let size = template.size() as usize;
+
+    if bytes.len() < size {
+        return Err(DecodeError::Truncated {
+            needed: size,
+            available: bytes.len(),
+        });
+    }
+
+    let result = match size {
+        3 => template.with_operand(join_bytes(bytes[2], bytes[1])),
+        2 => template.with_operand(bytes[1] as u16),
+        _ => template,
+    };
+
+    Ok((result, size))
+}
+
+/// Decodes the instruction at `address` without touching CPU state.
+/// Operands that run past the end of the address space wrap around to 0000h.
+///
+/// The executor fetches through this as well, so opcode and operand fetches use
+/// `Memory::peek` and are side effect free: memory mapped devices only see the
+/// data reads and writes of instructions, never the fetches.
+pub fn decode_at(bus: &Bus, address: u16) -> (Instruction, usize) {
+    let bytes = [
+        bus.peek_byte(address),
+        bus.peek_byte(address.wrapping_add(1)),
+        bus.peek_byte(address.wrapping_add(2)),
+    ];
+    decode(&bytes).expect("three bytes always hold a complete instruction")
+}
+
+const fn template(opcode: u8) -> Instruction {
+    match opcode {
         0x00 | 0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 => Instruction::Nop,
-        0x01 => Instruction::Lxi(Register::B, cpu.read_word()),
-        0x11 => Instruction::Lxi(Register::D, cpu.read_word()),
-        0x21 => Instruction::Lxi(Register::H, cpu.read_word()),
-        0x31 => Instruction::Lxi(Register::SP, cpu.read_word()),
+        0x01 => Instruction::Lxi(Register::B, 0),
+        0x11 => Instruction::Lxi(Register::D, 0),
+        0x21 => Instruction::Lxi(Register::H, 0),
+        0x31 => Instruction::Lxi(Register::SP, 0),
         0x02 => Instruction::Stax(Register::B),
         0x12 => Instruction::Stax(Register::D),
-        0x22 => Instruction::Shld(cpu.read_word()),
-        0x32 => Instruction::Sta(cpu.read_word()),
+        0x22 => Instruction::Shld(0),
+        0x32 => Instruction::Sta(0),
         0x03 => Instruction::Inx(Register::B),
         0x13 => Instruction::Inx(Register::D),
         0x23 => Instruction::Inx(Register::H),
@@ -118,7 +450,7 @@ pub fn decode_instruction(opcode: u8, cpu: &mut CPU) -> Instruction {
         0x0C => Instruction::Inr(Register::C),
         0x1C => Instruction::Inr(Register::E),
         0x2C => Instruction::Inr(Register::L),
-        0x3C => Instruction::Inx(Register::A),
+        0x3C => Instruction::Inr(Register::A),
         0x05 => Instruction::Dcr(Register::B),
         0x15 => Instruction::Dcr(Register::D),
         0x25 => Instruction::Dcr(Register::H),
@@ -127,14 +459,14 @@ pub fn decode_instruction(opcode: u8, cpu: &mut CPU) -> Instruction {
         0x1D => Instruction::Dcr(Register::E),
         0x2D => Instruction::Dcr(Register::L),
         0x3D => Instruction::Dcr(Register::A),
-        0x06 => Instruction::Mvi(Register::B, cpu.read_byte()),
-        0x16 => Instruction::Mvi(Register::D, cpu.read_byte()),
-        0x26 => Instruction::Mvi(Register::H, cpu.read_byte()),
-        0x36 => Instruction::Mvi(Register::M, cpu.read_byte()),
-        0x0E => Instruction::Mvi(Register::C, cpu.read_byte()),
-        0x1E => Instruction::Mvi(Register::E, cpu.read_byte()),
-        0x2E => Instruction::Mvi(Register::L, cpu.read_byte()),
-        0x3E => Instruction::Mvi(Register::A, cpu.read_byte()),
+        0x06 => Instruction::Mvi(Register::B, 0),
+        0x16 => Instruction::Mvi(Register::D, 0),
+        0x26 => Instruction::Mvi(Register::H, 0),
+        0x36 => Instruction::Mvi(Register::M, 0),
+        0x0E => Instruction::Mvi(Register::C, 0),
+        0x1E => Instruction::Mvi(Register::E, 0),
+        0x2E => Instruction::Mvi(Register::L, 0),
+        0x3E => Instruction::Mvi(Register::A, 0),
         0x07 => Instruction::Rlc,
         0x17 => Instruction::Ral,
         0x27 => Instruction::Daa,
@@ -145,8 +477,8 @@ pub fn decode_instruction(opcode: u8, cpu: &mut CPU) -> Instruction {
         0x39 => Instruction::Dad(Register::SP),
         0x0A => Instruction::Ldax(Register::B),
         0x1A => Instruction::Ldax(Register::D),
-        0x2A => Instruction::Lhld(cpu.read_word()),
-        0x3A => Instruction::Lda(cpu.read_word()),
+        0x2A => Instruction::Lhld(0),
+        0x3A => Instruction::Lda(0),
         0x0B => Instruction::Dcx(Register::B),
         0x1B => Instruction::Dcx(Register::D),
         0x2B => Instruction::Dcx(Register::H),
@@ -291,26 +623,26 @@ pub fn decode_instruction(opcode: u8, cpu: &mut CPU) -> Instruction {
         0xD1 => Instruction::Pop(Register::D),
         0xE1 => Instruction::Pop(Register::H),
         0xF1 => Instruction::Pop(Register::A),
-        0xC2 => Instruction::Jnz(cpu.read_word()),
-        0xD2 => Instruction::Jnc(cpu.read_word()),
-        0xE2 => Instruction::Jpo(cpu.read_word()),
-        0xF2 => Instruction::Jp(cpu.read_word()),
-        0xC3 | 0xCB => Instruction::Jmp(cpu.read_word()),
-        0xD3 => Instruction::Out(cpu.read_byte()),
+        0xC2 => Instruction::Jnz(0),
+        0xD2 => Instruction::Jnc(0),
+        0xE2 => Instruction::Jpo(0),
+        0xF2 => Instruction::Jp(0),
+        0xC3 | 0xCB => Instruction::Jmp(0),
+        0xD3 => Instruction::Out(0),
         0xE3 => Instruction::Xthl,
         0xF3 => Instruction::Di,
-        0xC4 => Instruction::Cnz(cpu.read_word()),
-        0xD4 => Instruction::Cnc(cpu.read_word()),
-        0xE4 => Instruction::Cpo(cpu.read_word()),
-        0xF4 => Instruction::Cp(cpu.read_word()),
+        0xC4 => Instruction::Cnz(0),
+        0xD4 => Instruction::Cnc(0),
+        0xE4 => Instruction::Cpo(0),
+        0xF4 => Instruction::Cp(0),
         0xC5 => Instruction::Push(Register::B),
         0xD5 => Instruction::Push(Register::D),
         0xE5 => Instruction::Push(Register::H),
         0xF5 => Instruction::Push(Register::A),
-        0xC6 => Instruction::Adi(cpu.read_byte()),
-        0xD6 => Instruction::Sui(cpu.read_byte()),
-        0xE6 => Instruction::Ani(cpu.read_byte()),
-        0xF6 => Instruction::Ori(cpu.read_byte()),
+        0xC6 => Instruction::Adi(0),
+        0xD6 => Instruction::Sui(0),
+        0xE6 => Instruction::Ani(0),
+        0xF6 => Instruction::Ori(0),
         0xC7 => Instruction::Rst(0),
         0xD7 => Instruction::Rst(2),
         0xE7 => Instruction::Rst(4),
@@ -322,29 +654,73 @@ pub fn decode_instruction(opcode: u8, cpu: &mut CPU) -> Instruction {
         0xC9 | 0xD9 => Instruction::Ret,
         0xE9 => Instruction::Pchl,
         0xF9 => Instruction::Sphl,
-        0xCA => Instruction::Jz(cpu.read_word()),
-        0xDA => Instruction::Jc(cpu.read_word()),
-        0xEA => Instruction::Jpe(cpu.read_word()),
-        0xFA => Instruction::Jm(cpu.read_word()),
-        0xDB => Instruction::In(cpu.read_byte()),
+        0xCA => Instruction::Jz(0),
+        0xDA => Instruction::Jc(0),
+        0xEA => Instruction::Jpe(0),
+        0xFA => Instruction::Jm(0),
+        0xDB => Instruction::In(0),
         0xEB => Instruction::Xchg,
         0xFB => Instruction::Ei,
-        0xCC => Instruction::Cz(cpu.read_word()),
-        0xDC => Instruction::Cc(cpu.read_word()),
-        0xEC => Instruction::Cpe(cpu.read_word()),
-        0xFC => Instruction::Cm(cpu.read_word()),
-        0xCD | 0xDD | 0xED | 0xFD => Instruction::Call(cpu.read_word()),
-        0xCE => Instruction::Aci(cpu.read_byte()),
-        0xDE => Instruction::Sbi(cpu.read_byte()),
-        0xEE => Instruction::Xri(cpu.read_byte()),
-        0xFE => Instruction::Cpi(cpu.read_byte()),
+        0xCC => Instruction::Cz(0),
+        0xDC => Instruction::Cc(0),
+        0xEC => Instruction::Cpe(0),
+        0xFC => Instruction::Cm(0),
+        0xCD | 0xDD | 0xED | 0xFD => Instruction::Call(0),
+        0xCE => Instruction::Aci(0),
+        0xDE => Instruction::Sbi(0),
+        0xEE => Instruction::Xri(0),
+        0xFE => Instruction::Cpi(0),
         0xCF => Instruction::Rst(1),
         0xDF => Instruction::Rst(3),
         0xEF => Instruction::Rst(5),
         0xFF => Instruction::Rst(7),
-    };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_reads_operands_from_the_slice() {
+        assert_eq!(decode(&[0x00]), Ok((Instruction::Nop, 1)));
+        assert_eq!(
+            decode(&[0x3E, 0x42]),
+            Ok((Instruction::Mvi(Register::A, 0x42), 2))
+        );
+        assert_eq!(
+            decode(&[0x31, 0x34, 0x12, 0xFF]),
+            Ok((Instruction::Lxi(Register::SP, 0x1234), 3))
+        );
+    }
+
+    #[test]
+    fn decode_rejects_short_slices() {
+        assert_eq!(decode(&[]), Err(DecodeError::Empty));
+        assert_eq!(
+            decode(&[0xC3, 0x00]),
+            Err(DecodeError::Truncated {
+                needed: 3,
+                available: 2
+            })
+        );
+        assert_eq!(
+            decode(&[0xFE]),
+            Err(DecodeError::Truncated {
+                needed: 2,
+                available: 1
+            })
+        );
+    }
 
-    println!("[DECODER]: Decoded opcode {:02X}h to {:?}", opcode, result);
+    #[test]
+    fn decode_at_wraps_operands_at_ffff() {
+        let mut bus = Bus::new();
+        bus.load_bytes(0xFFFE, &[0xC3, 0x34]).unwrap();
+        bus.load_bytes(0x0000, &[0x12]).unwrap();
+        assert_eq!(decode_at(&bus, 0xFFFE), (Instruction::Jmp(0x1234), 3));
 
-    result
+        bus.load_bytes(0xFFFF, &[0xD3]).unwrap();
+        assert_eq!(decode_at(&bus, 0xFFFF), (Instruction::Out(0x12), 2));
+    }
 }
@@ -0,0 +1,343 @@
+use super::bus::*;
+use super::decoder::*;
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HexStyle {
+    /// `0DEADh`, as accepted by Intel's assemblers
+    Intel,
+    /// `$DEAD`
+    Dollar,
+}
+
+impl HexStyle {
+    pub fn format(&self, value: u16, digits: usize) -> String {
+        let hex = format!("{:0width$X}", value, width = digits);
+        match self {
+            // A leading zero keeps numbers starting with a letter from being read as labels
+            HexStyle::Intel if hex.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                format!("0{}h", hex)
+            }
+            HexStyle::Intel => format!("{}h", hex),
+            HexStyle::Dollar => format!("${}", hex),
+        }
+    }
+}
+
+/// An instruction formatted with a specific hex style
+pub struct InstructionDisplay {
+    instruction: Instruction,
+    style: HexStyle,
+}
+
+impl Instruction {
+    pub fn display(&self, style: HexStyle) -> InstructionDisplay {
+        InstructionDisplay {
+            instruction: *self,
+            style,
+        }
+    }
+}
+
+impl fmt::Display for InstructionDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let instruction = self.instruction;
+        let mnemonic = instruction.mnemonic();
+        let hex = |value: u16, digits: usize| self.style.format(value, digits);
+
+        match instruction {
+            Instruction::Lxi(reg, value) => write!(f, "{} {},{}", mnemonic, reg, hex(value, 4)),
+            Instruction::Mvi(reg, value) => {
+                write!(f, "{} {},{}", mnemonic, reg, hex(value as u16, 2))
+            }
+            Instruction::Mov(dst, src) => write!(f, "{} {},{}", mnemonic, dst, src),
+            // PUSH and POP name the accumulator together with the flags
+            Instruction::Push(Register::A) | Instruction::Pop(Register::A) => {
+                write!(f, "{} PSW", mnemonic)
+            }
+            Instruction::Stax(reg)
+            | Instruction::Inx(reg)
+            | Instruction::Inr(reg)
+            | Instruction::Dcr(reg)
+            | Instruction::Dad(reg)
+            | Instruction::Ldax(reg)
+            | Instruction::Dcx(reg)
+            | Instruction::Add(reg)
+            | Instruction::Adc(reg)
+            | Instruction::Sub(reg)
+            | Instruction::Sbb(reg)
+            | Instruction::Ana(reg)
+            | Instruction::Xra(reg)
+            | Instruction::Ora(reg)
+            | Instruction::Cmp(reg)
+            | Instruction::Pop(reg)
+            | Instruction::Push(reg) => write!(f, "{} {}", mnemonic, reg),
+            Instruction::Rst(n) => write!(f, "{} {}", mnemonic, n),
+            _ => match instruction.size() {
+                3 => write!(f, "{} {}", mnemonic, hex(instruction.operand(), 4)),
+                2 => write!(f, "{} {}", mnemonic, hex(instruction.operand(), 2)),
+                _ => write!(f, "{}", mnemonic),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.display(HexStyle::Intel))
+    }
+}
+
+/// One line of a listing: either a decoded instruction or a data byte
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    /// `None` for bytes that were not reached as code
+    pub instruction: Option<Instruction>,
+    pub style: HexStyle,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        write!(f, "{:04X}  {:<8}  ", self.address, bytes.join(" "))?;
+        match self.instruction {
+            Some(instruction) => write!(f, "{}", instruction.display(self.style)),
+            None => write!(f, "DB {}", self.style.format(self.bytes[0] as u16, 2)),
+        }
+    }
+}
+
+pub struct Disassembler {
+    style: HexStyle,
+}
+
+impl Disassembler {
+    pub fn new(style: HexStyle) -> Self {
+        Self { style }
+    }
+
+    /// Disassembles the single instruction at `address`
+    pub fn line_at(&self, bus: &Bus, address: u16) -> Line {
+        let (instruction, size) = decode_at(bus, address);
+        self.code_line(bus, address, instruction, size)
+    }
+
+    /// Decodes every instruction from `start` up to and including `end`, treating all bytes as code
+    pub fn linear_sweep(&self, bus: &Bus, start: u16, end: u16) -> Vec<Line> {
+        let mut lines = Vec::new();
+        let mut address = start as u32;
+        while address <= end as u32 {
+            let line = self.line_at(bus, address as u16);
+            address += line.bytes.len() as u32;
+            lines.push(line);
+        }
+        lines
+    }
+
+    /// Follows the control flow from `entry_points` within `start..=end`. Reached
+    /// instructions are listed as code, everything else as data bytes.
+    pub fn recursive_descent(
+        &self,
+        bus: &Bus,
+        start: u16,
+        end: u16,
+        entry_points: &[u16],
+    ) -> Vec<Line> {
+        let code = self.find_code(bus, start, end, entry_points);
+
+        let mut lines = Vec::new();
+        let mut address = start as u32;
+        while address <= end as u32 {
+            let line = if code.contains(&(address as u16)) {
+                self.line_at(bus, address as u16)
+            } else {
+                Line {
+                    address: address as u16,
+                    bytes: vec![bus.peek_byte(address as u16)],
+                    instruction: None,
+                    style: self.style,
+                }
+            };
+            address += line.bytes.len() as u32;
+            lines.push(line);
+        }
+        lines
+    }
+
+    /// Returns the start addresses of all instructions reachable from `entry_points`
+    pub fn find_code(
+        &self,
+        bus: &Bus,
+        start: u16,
+        end: u16,
+        entry_points: &[u16],
+    ) -> BTreeSet<u16> {
+        let in_range = |address: u16| start <= address && address <= end;
+        let mut code = BTreeSet::new();
+        let mut pending: Vec<u16> = entry_points
+            .iter()
+            .copied()
+            .filter(|a| in_range(*a))
+            .collect();
+
+        while let Some(address) = pending.pop() {
+            if !in_range(address) || !code.insert(address) {
+                continue;
+            }
+
+            let (instruction, size) = decode_at(bus, address);
+            let next = address.wrapping_add(size as u16);
+
+            if let Some(target) = branch_target(instruction) {
+                pending.push(target);
+            }
+            if falls_through(instruction) && next > address {
+                pending.push(next);
+            }
+        }
+        code
+    }
+
+    fn code_line(&self, bus: &Bus, address: u16, instruction: Instruction, size: usize) -> Line {
+        Line {
+            address,
+            bytes: (0..size as u16)
+                .map(|i| bus.peek_byte(address.wrapping_add(i)))
+                .collect(),
+            instruction: Some(instruction),
+            style: self.style,
+        }
+    }
+}
+
+/// Static jump or call target of an instruction
+fn branch_target(instruction: Instruction) -> Option<u16> {
+    match instruction {
+        Instruction::Jmp(addr)
+        | Instruction::Jnz(addr)
+        | Instruction::Jz(addr)
+        | Instruction::Jnc(addr)
+        | Instruction::Jc(addr)
+        | Instruction::Jpo(addr)
+        | Instruction::Jpe(addr)
+        | Instruction::Jp(addr)
+        | Instruction::Jm(addr)
+        | Instruction::Call(addr)
+        | Instruction::Cnz(addr)
+        | Instruction::Cz(addr)
+        | Instruction::Cnc(addr)
+        | Instruction::Cc(addr)
+        | Instruction::Cpo(addr)
+        | Instruction::Cpe(addr)
+        | Instruction::Cp(addr)
+        | Instruction::Cm(addr) => Some(addr),
+        Instruction::Rst(n) => Some((n as u16) << 3),
+        _ => None,
+    }
+}
+
+/// Whether execution can continue with the following instruction
+fn falls_through(instruction: Instruction) -> bool {
+    !matches!(
+        instruction,
+        Instruction::Jmp(_) | Instruction::Ret | Instruction::Pchl
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Jumps over a string, calls a subroutine and returns, followed by a stray byte
+    const PROGRAM: [u8; 15] = [
+        0xC3, 0x07, 0x00, // JMP 0007h
+        0x48, 0x49, 0x00, 0xFF, // data
+        0xCD, 0x0B, 0x00, // CALL 000Bh
+        0xC9, // RET
+        0x3E, 0x42, // MVI A,42h
+        0xC9, // RET
+        0x3E, // data
+    ];
+
+    fn bus() -> Bus {
+        let mut bus = Bus::new();
+        bus.load_bytes(0, &PROGRAM).unwrap();
+        bus
+    }
+
+    #[test]
+    fn find_code_follows_jumps_and_calls() {
+        let code = Disassembler::new(HexStyle::Intel).find_code(&bus(), 0, 0x0E, &[0]);
+        assert_eq!(
+            code.into_iter().collect::<Vec<_>>(),
+            [0x00, 0x07, 0x0A, 0x0B, 0x0D]
+        );
+    }
+
+    #[test]
+    fn find_code_stays_within_range() {
+        let disassembler = Disassembler::new(HexStyle::Intel);
+        // The call target and the bytes after the RET lie outside the range
+        let code = disassembler.find_code(&bus(), 0x07, 0x0A, &[0x07]);
+        assert_eq!(code.into_iter().collect::<Vec<_>>(), [0x07, 0x0A]);
+        assert!(disassembler.find_code(&bus(), 0x07, 0x0A, &[0]).is_empty());
+    }
+
+    #[test]
+    fn find_code_follows_rst() {
+        let mut bus = Bus::new();
+        bus.load_bytes(0, &[0xCF, 0xC9]).unwrap();
+        bus.load_bytes(0x08, &[0xC9]).unwrap();
+        let code = Disassembler::new(HexStyle::Intel).find_code(&bus, 0, 0x08, &[0]);
+        assert_eq!(code.into_iter().collect::<Vec<_>>(), [0x00, 0x01, 0x08]);
+    }
+
+    #[test]
+    fn recursive_descent_lists_unreached_bytes_as_data() {
+        let listing: Vec<String> = Disassembler::new(HexStyle::Intel)
+            .recursive_descent(&bus(), 0, 0x0E, &[0])
+            .iter()
+            .map(|line| line.to_string())
+            .collect();
+        assert_eq!(
+            listing,
+            [
+                "0000  C3 07 00  JMP 0007h",
+                "0003  48        DB 48h",
+                "0004  49        DB 49h",
+                "0005  00        DB 00h",
+                "0006  FF        DB 0FFh",
+                "0007  CD 0B 00  CALL 000Bh",
+                "000A  C9        RET",
+                "000B  3E 42     MVI A,42h",
+                "000D  C9        RET",
+                "000E  3E        DB 3Eh",
+            ]
+        );
+    }
+
+    #[test]
+    fn dollar_style_prefixes_numbers() {
+        assert_eq!(HexStyle::Dollar.format(0xBEEF, 4), "$BEEF");
+        assert_eq!(HexStyle::Dollar.format(0x0A, 2), "$0A");
+        assert_eq!(HexStyle::Intel.format(0xBEEF, 4), "0BEEFh");
+
+        let display = |instruction: Instruction| instruction.display(HexStyle::Dollar).to_string();
+        assert_eq!(display(Instruction::Jmp(0xBEEF)), "JMP $BEEF");
+        assert_eq!(display(Instruction::Mvi(Register::A, 0x0A)), "MVI A,$0A");
+        assert_eq!(display(Instruction::Out(0xFE)), "OUT $FE");
+        assert_eq!(display(Instruction::Push(Register::A)), "PUSH PSW");
+
+        let listing: Vec<String> = Disassembler::new(HexStyle::Dollar)
+            .recursive_descent(&bus(), 0, 0x07, &[0])
+            .iter()
+            .map(|line| line.to_string())
+            .collect();
+        assert_eq!(listing[0], "0000  C3 07 00  JMP $0007");
+        assert_eq!(listing[4], "0006  FF        DB $FF");
+    }
+}
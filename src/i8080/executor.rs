@@ -1,13 +1,62 @@
+use super::alu;
+use super::breakpoint::*;
 use super::cpu::*;
 use super::decoder::*;
+use super::history::*;
+use super::trace::*;
 use super::util::*;
 
-use std::collections::HashMap;
+use std::error;
+use std::fmt;
+
+/// Cycles spent per `execute` call while the CPU is halted
+const HALT_IDLE_CYCLES: usize = 4;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExecuteError {
+    Not8BitRegister(Register),
+    Not16BitRegister(Register),
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecuteError::Not8BitRegister(reg) => write!(f, "{:?} is not a 8 Bit register", reg),
+            ExecuteError::Not16BitRegister(reg) => write!(f, "{:?} is not a 16 Bit register", reg),
+        }
+    }
+}
+
+impl error::Error for ExecuteError {}
+
+/// Why one of the `run_*` loops returned
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// The cycle or instruction budget is used up
+    BudgetExhausted,
+    /// The CPU executed HLT and waits for an interrupt
+    Halted,
+    /// The condition passed to `run_until` became true
+    ConditionMet,
+    /// PC reached a breakpoint, the instruction there has not been executed yet
+    Breakpoint(u16),
+    /// The last instruction accessed a watched memory address
+    Watchpoint(WatchHit),
+    /// The last instruction accessed a watched I/O port
+    PortWatchpoint(WatchHit),
+    /// A register condition holds after the last instruction
+    Condition(Condition),
+    /// Reverse execution reached the oldest recorded instruction
+    StartOfHistory,
+    Error(ExecuteError),
+}
 
 pub struct Executor<'a> {
     cpu: &'a mut CPU,
     cycles: usize,
-    cache: HashMap<u8, Instruction>,
+    instruction_trace: Option<InstructionTrace>,
+    breakpoints: Breakpoints,
+    history: Option<History>,
 }
 
 impl<'a> Executor<'a> {
@@ -15,28 +64,246 @@ impl<'a> Executor<'a> {
         Self {
             cpu,
             cycles: 0,
-            cache: HashMap::new(),
+            instruction_trace: None,
+            breakpoints: Breakpoints::new(),
+            history: None,
         }
     }
 
-    pub fn execute(&mut self) {
-        let opcode = self.cpu.read_byte();
+    pub fn cpu(&self) -> &CPU {
+        self.cpu
+    }
 
-        println!("[EXECUTOR]: Executing opcode {:02X}h", opcode);
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        self.cpu
+    }
+
+    pub fn breakpoints(&self) -> &Breakpoints {
+        &self.breakpoints
+    }
+
+    /// Execute breakpoints and conditions. Watchpoints are set on `cpu.bus` and `cpu.io`.
+    pub fn breakpoints_mut(&mut self) -> &mut Breakpoints {
+        &mut self.breakpoints
+    }
 
-        if !self.cache.contains_key(&opcode) {
-            self.cache
-                .insert(opcode, decode_instruction(opcode, &mut self.cpu));
-            println!("[EXECUTOR]: Saving instruction implementation to cache");
-        } else {
-            println!("[EXECUTOR]: Loading instruction implementation from cache");
+    pub fn execute(&mut self) -> Result<(), ExecuteError> {
+        if self.cpu.halted {
+            // A halted CPU keeps running idle machine cycles until an interrupt arrives
+            self.cycles += HALT_IDLE_CYCLES;
+            return Ok(());
         }
 
-        let instruction = *self.cache.get(&opcode).unwrap();
-        println!("[EXECUTOR]: Running cached instruction {:?}", instruction);
-        let (size, cycles) = self.execute_instruction(instruction);
-        self.cpu.pc += size;
+        let undo = self.begin_undo();
+        let pc = self.cpu.pc;
+        let (instruction, size) = decode_at(&self.cpu.bus, pc);
+
+        self.cpu.tracer().trace(&TraceEvent::Decode {
+            address: pc,
+            instruction,
+        });
+
+        if let Some(trace) = self.instruction_trace.as_mut() {
+            trace.record(self.cpu, self.cycles, instruction);
+        }
+
+        // EI only takes effect after the instruction that follows it
+        self.cpu.interrupt_delay = false;
+
+        // Jumps, calls and returns overwrite PC again while executing
+        self.cpu.pc = pc.wrapping_add(size as u16);
+        let result = self.execute_instruction(instruction);
+        self.end_undo(undo);
+        let cycles = result?;
         self.cycles += cycles;
+
+        self.cpu.tracer().trace(&TraceEvent::Retire {
+            address: pc,
+            instruction,
+            cycles,
+        });
+        Ok(())
+    }
+
+    /// Lets a device place `instruction` on the data bus, usually an RST.
+    /// The interrupt is only accepted while INTE is set and not directly after EI,
+    /// accepting it clears INTE. Returns whether the instruction was executed.
+    pub fn interrupt(&mut self, instruction: Instruction) -> Result<bool, ExecuteError> {
+        if !self.cpu.inte || self.cpu.interrupt_delay {
+            return Ok(false);
+        }
+
+        self.cpu
+            .tracer()
+            .trace(&TraceEvent::Interrupt { instruction });
+
+        // PC is not advanced, so an RST pushes the address of the interrupted instruction
+        let undo = self.begin_undo();
+        self.cpu.inte = false;
+        self.cpu.halted = false;
+        let result = self.execute_instruction(instruction);
+        self.end_undo(undo);
+        self.cycles += result?;
+        Ok(true)
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.cpu.halted
+    }
+
+    /// Executes instructions until the CPU reaches a HLT instruction
+    pub fn run_until_halt(&mut self) -> StopReason {
+        self.run_until(|_| false)
+    }
+
+    /// Runs until at least `budget` cycles have passed. A halted CPU idles
+    /// through the rest of the budget so that timed slices stay accurate.
+    pub fn run_cycles(&mut self, budget: usize) -> StopReason {
+        let target = self.cycles + budget;
+        while self.cycles < target {
+            if self.cpu.halted {
+                self.cycles = target;
+                return StopReason::Halted;
+            }
+            if let Some(reason) = self.step() {
+                return reason;
+            }
+        }
+        StopReason::BudgetExhausted
+    }
+
+    pub fn run_instructions(&mut self, count: usize) -> StopReason {
+        for _ in 0..count {
+            if let Some(reason) = self.step() {
+                return reason;
+            }
+        }
+        StopReason::BudgetExhausted
+    }
+
+    /// Runs until `condition` holds after an instruction, the CPU halts or an error occurs
+    pub fn run_until<F>(&mut self, mut condition: F) -> StopReason
+    where
+        F: FnMut(&CPU) -> bool,
+    {
+        loop {
+            if let Some(reason) = self.step() {
+                return reason;
+            }
+            if condition(self.cpu) {
+                return StopReason::ConditionMet;
+            }
+        }
+    }
+
+    /// Executes one instruction, returns a reason if the run loop has to stop
+    fn step(&mut self) -> Option<StopReason> {
+        if self.cpu.halted {
+            return Some(StopReason::Halted);
+        }
+
+        // Only accesses of this instruction count, not those of the host or an interrupt
+        self.cpu.bus.watchpoints().take_hit();
+        self.cpu.io.watchpoints().take_hit();
+
+        if let Err(error) = self.execute() {
+            return Some(StopReason::Error(error));
+        }
+
+        if let Some(hit) = self.cpu.bus.watchpoints().take_hit() {
+            return Some(StopReason::Watchpoint(hit));
+        }
+        if let Some(hit) = self.cpu.io.watchpoints().take_hit() {
+            return Some(StopReason::PortWatchpoint(hit));
+        }
+        if let Some(address) = self.breakpoints.breakpoint_hit(self.cpu) {
+            return Some(StopReason::Breakpoint(address));
+        }
+        self.breakpoints
+            .condition_hit(self.cpu)
+            .map(StopReason::Condition)
+    }
+
+    /// Starts writing a line per executed instruction to `trace`
+    pub fn set_instruction_trace(&mut self, trace: InstructionTrace) {
+        self.instruction_trace = Some(trace);
+    }
+
+    /// Stops the instruction trace and hands it back, e.g. to call `finish` on it
+    pub fn take_instruction_trace(&mut self) -> Option<InstructionTrace> {
+        self.instruction_trace.take()
+    }
+
+    /// Starts recording the last `limit` instructions so that they can be taken back
+    pub fn enable_history(&mut self, limit: usize) {
+        self.history = Some(History::new(limit));
+    }
+
+    pub fn disable_history(&mut self) {
+        self.history = None;
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// Forgets all recorded instructions, e.g. after the state was changed from outside
+    pub fn clear_history(&mut self) {
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
+    }
+
+    /// Takes back the last recorded instruction. Returns false if there is none.
+    pub fn reverse_step(&mut self) -> bool {
+        let record = match self.history.as_mut().and_then(History::pop) {
+            Some(record) => record,
+            None => return false,
+        };
+
+        for write in record.writes.iter().rev() {
+            self.cpu.bus.poke_byte(write.address, write.old);
+        }
+        record.registers.restore(self.cpu);
+        self.cycles = record.cycles;
+        true
+    }
+
+    /// Steps backwards until PC is at a breakpoint or a condition holds
+    pub fn reverse_continue(&mut self) -> StopReason {
+        loop {
+            if !self.reverse_step() {
+                return StopReason::StartOfHistory;
+            }
+            if let Some(address) = self.breakpoints.breakpoint_hit(self.cpu) {
+                return StopReason::Breakpoint(address);
+            }
+            if let Some(condition) = self.breakpoints.condition_hit(self.cpu) {
+                return StopReason::Condition(condition);
+            }
+        }
+    }
+
+    /// Finds the recorded instruction that wrote `address` most recently
+    pub fn last_write(&self, address: u16) -> Option<LastWrite> {
+        self.history.as_ref()?.last_write(address)
+    }
+
+    fn begin_undo(&mut self) -> Option<UndoRecord> {
+        self.history.as_ref()?;
+        self.cpu.bus.start_journal();
+        Some(UndoRecord {
+            registers: RegisterSnapshot::capture(self.cpu),
+            cycles: self.cycles,
+            writes: Vec::new(),
+        })
+    }
+
+    fn end_undo(&mut self, undo: Option<UndoRecord>) {
+        if let (Some(mut record), Some(history)) = (undo, self.history.as_mut()) {
+            record.writes = self.cpu.bus.take_journal();
+            history.push(record);
+        }
     }
 
     pub fn get_cycles(&self) -> usize {
@@ -47,36 +314,50 @@ impl<'a> Executor<'a> {
         self.cycles = 0;
     }
 
-    fn write_reg16(&mut self, reg: Register, value: u16) {
-        println!("[EXECUTOR]: Writing {:04X}h to {:?}", value, reg);
+    pub fn set_cycles(&mut self, cycles: usize) {
+        self.cycles = cycles;
+    }
+
+    fn write_reg16(&mut self, reg: Register, value: u16) -> Result<(), ExecuteError> {
+        self.cpu.tracer().trace(&TraceEvent::RegisterWrite {
+            register: reg,
+            value,
+        });
         match reg {
             Register::A => self.cpu.set_psw(value),
             Register::B => self.cpu.set_bc(value),
             Register::D => self.cpu.set_de(value),
             Register::H => self.cpu.set_hl(value),
             Register::SP => self.cpu.sp = value,
-            _ => panic!("{:?} is not a 16 Bit register", reg),
+            _ => return Err(ExecuteError::Not16BitRegister(reg)),
         };
+        Ok(())
     }
 
-    fn read_reg16(&self, reg: Register) -> u16 {
+    fn read_reg16(&self, reg: Register) -> Result<u16, ExecuteError> {
         let result = match reg {
             Register::A => self.cpu.get_psw(),
             Register::B => self.cpu.get_bc(),
             Register::D => self.cpu.get_de(),
             Register::H => self.cpu.get_hl(),
             Register::SP => self.cpu.sp,
-            _ => panic!("{:?} is not a 16 Bit register", reg),
+            _ => return Err(ExecuteError::Not16BitRegister(reg)),
         };
-        println!("[EXECUTOR]: Reading {:04X}h from {:?}", result, reg);
-        result
+        self.cpu.tracer().trace(&TraceEvent::RegisterRead {
+            register: reg,
+            value: result,
+        });
+        Ok(result)
     }
 
-    fn write_reg8(&mut self, reg: Register, value: u8) {
-        println!("[EXECUTOR]: Writing {:02X}h to {:?}", value, reg);
+    fn write_reg8(&mut self, reg: Register, value: u8) -> Result<(), ExecuteError> {
+        self.cpu.tracer().trace(&TraceEvent::RegisterWrite {
+            register: reg,
+            value: value as u16,
+        });
         match reg {
             Register::A => self.cpu.a = value,
-            Register::Flags => self.cpu.flags = value,
+            Register::Flags => self.cpu.set_flags(value),
             Register::B => self.cpu.b = value,
             Register::C => self.cpu.c = value,
             Register::D => self.cpu.d = value,
@@ -84,11 +365,12 @@ impl<'a> Executor<'a> {
             Register::H => self.cpu.h = value,
             Register::L => self.cpu.l = value,
             Register::M => self.cpu.bus.write_byte(self.cpu.get_hl(), value),
-            _ => panic!("{:?} is not a 8 Bit register", reg),
+            _ => return Err(ExecuteError::Not8BitRegister(reg)),
         };
+        Ok(())
     }
 
-    fn read_reg8(&mut self, reg: Register) -> u8 {
+    fn read_reg8(&mut self, reg: Register) -> Result<u8, ExecuteError> {
         let result = match reg {
             Register::A => self.cpu.a,
             Register::Flags => self.cpu.flags,
@@ -99,86 +381,1321 @@ impl<'a> Executor<'a> {
             Register::H => self.cpu.h,
             Register::L => self.cpu.l,
             Register::M => self.cpu.bus.read_byte(self.cpu.get_hl()),
-            _ => panic!("{:?} is not a 8 Bit register", reg),
+            _ => return Err(ExecuteError::Not8BitRegister(reg)),
         };
-        println!("[EXECUTOR]: Reading {:02X}h from {:?}", result, reg);
-        result
+        self.cpu.tracer().trace(&TraceEvent::RegisterRead {
+            register: reg,
+            value: result as u16,
+        });
+        Ok(result)
     }
 
-    fn check_flags(&mut self, value: u8) {
-        set_bit_enabled(&mut self.cpu.flags, ZERO_FLAG, value == 0);
-        set_bit_enabled(&mut self.cpu.flags, SIGN_FLAG, (value >> 7) == 0x1);
-        set_bit_enabled(
-            &mut self.cpu.flags,
-            PARITY_FLAG,
-            get_enabled_bits(value) > 0,
-        );
-        set_bit_enabled(&mut self.cpu.flags, AUX_CARRY_FLAG, false);
-    }
-
-    fn check_carry(&mut self, value: u16) {
-        set_bit_enabled(&mut self.cpu.flags, CARRY_FLAG, value > 0xFF);
-    }
-
-    fn execute_instruction(&mut self, instr: Instruction) -> (u16, usize) {
-        match instr {
-            Instruction::Nop => (1, 4),
+    fn execute_instruction(&mut self, instr: Instruction) -> Result<usize, ExecuteError> {
+        let cycles = match instr {
+            Instruction::Nop => 4,
             Instruction::Lxi(reg, value) => {
-                self.write_reg16(reg, value);
-                (3, 10)
+                self.write_reg16(reg, value)?;
+                10
             }
             Instruction::Stax(reg) => {
-                self.cpu.bus.write_byte(self.read_reg16(reg), self.cpu.a);
-                (1, 7)
+                self.cpu.bus.write_byte(self.read_reg16(reg)?, self.cpu.a);
+                7
             }
             Instruction::Shld(addr) => {
                 self.cpu.bus.write_byte(addr, self.cpu.l);
-                self.cpu.bus.write_byte(addr + 1, self.cpu.h);
-                (3, 16)
+                self.cpu.bus.write_byte(addr.wrapping_add(1), self.cpu.h);
+                16
             }
             Instruction::Sta(addr) => {
                 self.cpu.bus.write_byte(addr, self.cpu.a);
-                (3, 13)
+                13
             }
             Instruction::Inx(reg) => {
-                let tmp = self.read_reg16(reg);
-                self.write_reg16(reg, tmp.wrapping_add(1));
-                (1, 5)
+                let tmp = self.read_reg16(reg)?;
+                self.write_reg16(reg, tmp.wrapping_add(1))?;
+                5
             }
             Instruction::Inr(reg) => {
-                let result = self.read_reg8(reg).wrapping_add(1);
-                self.check_flags(result);
-                self.write_reg8(reg, result);
-                (1, 5)
+                let value = self.read_reg8(reg)?;
+                let result = alu::inr(&mut self.cpu.flags, value);
+                self.write_reg8(reg, result)?;
+                operand_cycles(reg, 5, 10)
             }
             Instruction::Dcr(reg) => {
-                let result = self.read_reg8(reg).wrapping_sub(1);
-                self.check_flags(result);
-                self.write_reg8(reg, result);
-                (1, 5)
+                let value = self.read_reg8(reg)?;
+                let result = alu::dcr(&mut self.cpu.flags, value);
+                self.write_reg8(reg, result)?;
+                operand_cycles(reg, 5, 10)
             }
             Instruction::Mvi(reg, value) => {
-                self.write_reg8(reg, value);
-                (2, 7)
+                self.write_reg8(reg, value)?;
+                operand_cycles(reg, 7, 10)
             }
             Instruction::Rlc => {
                 let bit = self.cpu.a >> 7;
                 self.cpu.a = (self.cpu.a << 1) | bit;
                 set_bit_enabled(&mut self.cpu.flags, CARRY_FLAG, bit == 1);
-                (1, 4)
+                4
             }
             Instruction::Ral => {
                 let bit = self.cpu.a >> 7;
                 self.cpu.a = (self.cpu.a << 1) | (get_bit(self.cpu.flags, CARRY_FLAG) as u8);
                 set_bit_enabled(&mut self.cpu.flags, CARRY_FLAG, bit == 1);
-                (1, 4)
+                4
+            }
+            Instruction::Daa => {
+                self.cpu.a = alu::daa(&mut self.cpu.flags, self.cpu.a);
+                4
             }
-            // Instruction::Daa
             Instruction::Stc => {
                 set_bit(&mut self.cpu.flags, CARRY_FLAG);
-                (1, 4)
+                4
+            }
+            Instruction::Dad(reg) => {
+                let result = self.cpu.get_hl() as u32 + self.read_reg16(reg)? as u32;
+                set_bit_enabled(&mut self.cpu.flags, CARRY_FLAG, result > 0xFFFF);
+                self.cpu.set_hl(result as u16);
+                10
+            }
+            Instruction::Ldax(reg) => {
+                self.cpu.a = self.cpu.bus.read_byte(self.read_reg16(reg)?);
+                7
+            }
+            Instruction::Lhld(addr) => {
+                self.cpu.l = self.cpu.bus.read_byte(addr);
+                self.cpu.h = self.cpu.bus.read_byte(addr.wrapping_add(1));
+                16
+            }
+            Instruction::Lda(addr) => {
+                self.cpu.a = self.cpu.bus.read_byte(addr);
+                13
+            }
+            Instruction::Dcx(reg) => {
+                let tmp = self.read_reg16(reg)?;
+                self.write_reg16(reg, tmp.wrapping_sub(1))?;
+                5
+            }
+            Instruction::Rrc => {
+                let bit = self.cpu.a & 0x1;
+                self.cpu.a = (self.cpu.a >> 1) | (bit << 7);
+                set_bit_enabled(&mut self.cpu.flags, CARRY_FLAG, bit == 1);
+                4
+            }
+            Instruction::Rar => {
+                let bit = self.cpu.a & 0x1;
+                self.cpu.a = (self.cpu.a >> 1) | ((get_bit(self.cpu.flags, CARRY_FLAG) as u8) << 7);
+                set_bit_enabled(&mut self.cpu.flags, CARRY_FLAG, bit == 1);
+                4
+            }
+            Instruction::Cma => {
+                self.cpu.a = !self.cpu.a;
+                4
+            }
+            Instruction::Cmc => {
+                let carry = get_bit(self.cpu.flags, CARRY_FLAG);
+                set_bit_enabled(&mut self.cpu.flags, CARRY_FLAG, !carry);
+                4
+            }
+            Instruction::Mov(dst, src) => {
+                let value = self.read_reg8(src)?;
+                self.write_reg8(dst, value)?;
+                operand_cycles(dst, 5, 7).max(operand_cycles(src, 5, 7))
+            }
+            Instruction::Hlt => {
+                self.cpu.halted = true;
+                7
+            }
+            Instruction::Add(reg) => {
+                let value = self.read_reg8(reg)?;
+                self.cpu.a = self.add(value, false);
+                operand_cycles(reg, 4, 7)
+            }
+            Instruction::Adc(reg) => {
+                let value = self.read_reg8(reg)?;
+                self.cpu.a = self.add(value, true);
+                operand_cycles(reg, 4, 7)
+            }
+            Instruction::Sub(reg) => {
+                let value = self.read_reg8(reg)?;
+                self.cpu.a = self.sub(value, false);
+                operand_cycles(reg, 4, 7)
+            }
+            Instruction::Sbb(reg) => {
+                let value = self.read_reg8(reg)?;
+                self.cpu.a = self.sub(value, true);
+                operand_cycles(reg, 4, 7)
+            }
+            Instruction::Ana(reg) => {
+                let value = self.read_reg8(reg)?;
+                self.cpu.a = alu::and(&mut self.cpu.flags, self.cpu.a, value);
+                operand_cycles(reg, 4, 7)
+            }
+            Instruction::Xra(reg) => {
+                let value = self.read_reg8(reg)?;
+                self.cpu.a = alu::xor(&mut self.cpu.flags, self.cpu.a, value);
+                operand_cycles(reg, 4, 7)
+            }
+            Instruction::Ora(reg) => {
+                let value = self.read_reg8(reg)?;
+                self.cpu.a = alu::or(&mut self.cpu.flags, self.cpu.a, value);
+                operand_cycles(reg, 4, 7)
+            }
+            Instruction::Cmp(reg) => {
+                let value = self.read_reg8(reg)?;
+                self.sub(value, false);
+                operand_cycles(reg, 4, 7)
+            }
+            Instruction::Adi(value) => {
+                self.cpu.a = self.add(value, false);
+                7
+            }
+            Instruction::Aci(value) => {
+                self.cpu.a = self.add(value, true);
+                7
+            }
+            Instruction::Sui(value) => {
+                self.cpu.a = self.sub(value, false);
+                7
+            }
+            Instruction::Sbi(value) => {
+                self.cpu.a = self.sub(value, true);
+                7
+            }
+            Instruction::Ani(value) => {
+                self.cpu.a = alu::and(&mut self.cpu.flags, self.cpu.a, value);
+                7
+            }
+            Instruction::Xri(value) => {
+                self.cpu.a = alu::xor(&mut self.cpu.flags, self.cpu.a, value);
+                7
+            }
+            Instruction::Ori(value) => {
+                self.cpu.a = alu::or(&mut self.cpu.flags, self.cpu.a, value);
+                7
+            }
+            Instruction::Cpi(value) => {
+                self.sub(value, false);
+                7
+            }
+            Instruction::Jmp(addr) => {
+                self.cpu.jump(addr);
+                10
+            }
+            Instruction::Jnz(addr) => self.jump_if(ZERO_FLAG, false, addr),
+            Instruction::Jz(addr) => self.jump_if(ZERO_FLAG, true, addr),
+            Instruction::Jnc(addr) => self.jump_if(CARRY_FLAG, false, addr),
+            Instruction::Jc(addr) => self.jump_if(CARRY_FLAG, true, addr),
+            Instruction::Jpo(addr) => self.jump_if(PARITY_FLAG, false, addr),
+            Instruction::Jpe(addr) => self.jump_if(PARITY_FLAG, true, addr),
+            Instruction::Jp(addr) => self.jump_if(SIGN_FLAG, false, addr),
+            Instruction::Jm(addr) => self.jump_if(SIGN_FLAG, true, addr),
+            Instruction::Call(addr) => {
+                self.call(addr);
+                17
+            }
+            Instruction::Cnz(addr) => self.call_if(ZERO_FLAG, false, addr),
+            Instruction::Cz(addr) => self.call_if(ZERO_FLAG, true, addr),
+            Instruction::Cnc(addr) => self.call_if(CARRY_FLAG, false, addr),
+            Instruction::Cc(addr) => self.call_if(CARRY_FLAG, true, addr),
+            Instruction::Cpo(addr) => self.call_if(PARITY_FLAG, false, addr),
+            Instruction::Cpe(addr) => self.call_if(PARITY_FLAG, true, addr),
+            Instruction::Cp(addr) => self.call_if(SIGN_FLAG, false, addr),
+            Instruction::Cm(addr) => self.call_if(SIGN_FLAG, true, addr),
+            Instruction::Ret => {
+                let addr = self.cpu.pop();
+                self.cpu.jump(addr);
+                10
+            }
+            Instruction::Rnz => self.return_if(ZERO_FLAG, false),
+            Instruction::Rz => self.return_if(ZERO_FLAG, true),
+            Instruction::Rnc => self.return_if(CARRY_FLAG, false),
+            Instruction::Rc => self.return_if(CARRY_FLAG, true),
+            Instruction::Rpo => self.return_if(PARITY_FLAG, false),
+            Instruction::Rpe => self.return_if(PARITY_FLAG, true),
+            Instruction::Rp => self.return_if(SIGN_FLAG, false),
+            Instruction::Rm => self.return_if(SIGN_FLAG, true),
+            Instruction::Rst(n) => {
+                self.call((n as u16) << 3);
+                11
+            }
+            Instruction::Push(reg) => {
+                let value = self.read_reg16(reg)?;
+                self.cpu.push(value);
+                11
+            }
+            Instruction::Pop(reg) => {
+                let value = self.cpu.pop();
+                self.write_reg16(reg, value)?;
+                10
+            }
+            Instruction::Xthl => {
+                // Swaps L with (SP) and H with (SP+1) while SP stays unchanged
+                let value = self.cpu.pop();
+                self.cpu.push(self.cpu.get_hl());
+                self.cpu.set_hl(value);
+                18
+            }
+            Instruction::Xchg => {
+                let de = self.cpu.get_de();
+                self.cpu.set_de(self.cpu.get_hl());
+                self.cpu.set_hl(de);
+                4
+            }
+            Instruction::Pchl => {
+                self.cpu.jump(self.cpu.get_hl());
+                5
+            }
+            Instruction::Sphl => {
+                self.cpu.sp = self.cpu.get_hl();
+                5
+            }
+            Instruction::In(port) => {
+                self.cpu.a = self.cpu.io.read(port);
+                10
+            }
+            Instruction::Out(port) => {
+                self.cpu.io.write(port, self.cpu.a);
+                10
+            }
+            Instruction::Ei => {
+                self.cpu.inte = true;
+                self.cpu.interrupt_delay = true;
+                4
+            }
+            Instruction::Di => {
+                self.cpu.inte = false;
+                4
+            }
+        };
+        Ok(cycles)
+    }
+
+    fn add(&mut self, value: u8, with_carry: bool) -> u8 {
+        let carry = with_carry && get_bit(self.cpu.flags, CARRY_FLAG);
+        alu::add(&mut self.cpu.flags, self.cpu.a, value, carry)
+    }
+
+    fn sub(&mut self, value: u8, with_borrow: bool) -> u8 {
+        let borrow = with_borrow && get_bit(self.cpu.flags, CARRY_FLAG);
+        alu::sub(&mut self.cpu.flags, self.cpu.a, value, borrow)
+    }
+
+    fn call(&mut self, addr: u16) {
+        self.cpu.push(self.cpu.pc);
+        self.cpu.jump(addr);
+    }
+
+    fn jump_if(&mut self, flag: usize, expected: bool, addr: u16) -> usize {
+        if get_bit(self.cpu.flags, flag) == expected {
+            self.cpu.jump(addr);
+        }
+        10
+    }
+
+    fn call_if(&mut self, flag: usize, expected: bool, addr: u16) -> usize {
+        if get_bit(self.cpu.flags, flag) == expected {
+            self.call(addr);
+            return 17;
+        }
+        11
+    }
+
+    fn return_if(&mut self, flag: usize, expected: bool) -> usize {
+        if get_bit(self.cpu.flags, flag) == expected {
+            let addr = self.cpu.pop();
+            self.cpu.jump(addr);
+            return 11;
+        }
+        5
+    }
+}
+
+/// Register operands take `cycles`, operating on memory through M takes `memory_cycles`
+fn operand_cycles(reg: Register, cycles: usize, memory_cycles: usize) -> usize {
+    if reg == Register::M {
+        memory_cycles
+    } else {
+        cycles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::io::*;
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const S: u8 = 0x80;
+    const Z: u8 = 0x40;
+    const AC: u8 = 0x10;
+    const P: u8 = 0x04;
+    const CY: u8 = 0x01;
+    /// Bit 1 of the flags always reads as 1
+    const FIXED: u8 = 0x02;
+
+    /// Executes the instruction at 0000h after `setup` prepared the CPU,
+    /// returns the CPU and the cycles the instruction took
+    fn execute<F: FnOnce(&mut CPU)>(program: &[u8], setup: F) -> (CPU, usize) {
+        let mut cpu = CPU::new();
+        cpu.bus.load_bytes(0, program).unwrap();
+        setup(&mut cpu);
+        let mut executor = Executor::new(&mut cpu);
+        executor.execute().unwrap();
+        let cycles = executor.get_cycles();
+        (cpu, cycles)
+    }
+
+    #[test]
+    fn nop() {
+        let (cpu, cycles) = execute(&[0x00], |_| {});
+        assert_eq!(cpu.pc, 0x0001);
+        assert_eq!(cpu.flags, FIXED);
+        assert_eq!(cycles, 4);
+    }
+
+    #[test]
+    fn lxi() {
+        for (opcode, get) in [
+            (0x01, CPU::get_bc as fn(&CPU) -> u16),
+            (0x11, CPU::get_de),
+            (0x21, CPU::get_hl),
+            (0x31, |cpu: &CPU| cpu.sp),
+        ] {
+            let (cpu, cycles) = execute(&[opcode, 0x34, 0x12], |_| {});
+            assert_eq!(get(&cpu), 0x1234, "opcode {:02X}", opcode);
+            assert_eq!(cpu.pc, 0x0003);
+            assert_eq!(cycles, 10);
+        }
+    }
+
+    #[test]
+    fn stax_and_ldax() {
+        let (cpu, cycles) = execute(&[0x02], |cpu| {
+            cpu.a = 0x42;
+            cpu.set_bc(0x2000);
+        });
+        assert_eq!(cpu.bus.peek_byte(0x2000), 0x42);
+        assert_eq!(cycles, 7);
+
+        let (cpu, _) = execute(&[0x12], |cpu| {
+            cpu.a = 0x43;
+            cpu.set_de(0x2001);
+        });
+        assert_eq!(cpu.bus.peek_byte(0x2001), 0x43);
+
+        let (cpu, cycles) = execute(&[0x0A], |cpu| {
+            cpu.bus.poke_byte(0x2000, 0x44);
+            cpu.set_bc(0x2000);
+        });
+        assert_eq!(cpu.a, 0x44);
+        assert_eq!(cycles, 7);
+
+        let (cpu, _) = execute(&[0x1A], |cpu| {
+            cpu.bus.poke_byte(0x2001, 0x45);
+            cpu.set_de(0x2001);
+        });
+        assert_eq!(cpu.a, 0x45);
+    }
+
+    #[test]
+    fn shld_and_lhld() {
+        let (cpu, cycles) = execute(&[0x22, 0x00, 0x20], |cpu| cpu.set_hl(0x1234));
+        assert_eq!(cpu.bus.peek_byte(0x2000), 0x34);
+        assert_eq!(cpu.bus.peek_byte(0x2001), 0x12);
+        assert_eq!(cpu.pc, 0x0003);
+        assert_eq!(cycles, 16);
+
+        let (cpu, cycles) = execute(&[0x2A, 0x00, 0x20], |cpu| {
+            cpu.bus.load_bytes(0x2000, &[0x78, 0x56]).unwrap();
+        });
+        assert_eq!(cpu.get_hl(), 0x5678);
+        assert_eq!(cycles, 16);
+    }
+
+    #[test]
+    fn sta_and_lda() {
+        let (cpu, cycles) = execute(&[0x32, 0x00, 0x20], |cpu| cpu.a = 0x42);
+        assert_eq!(cpu.bus.peek_byte(0x2000), 0x42);
+        assert_eq!(cycles, 13);
+
+        let (cpu, cycles) = execute(&[0x3A, 0x00, 0x20], |cpu| cpu.bus.poke_byte(0x2000, 0x43));
+        assert_eq!(cpu.a, 0x43);
+        assert_eq!(cycles, 13);
+    }
+
+    #[test]
+    fn inx_and_dcx_wrap_without_touching_flags() {
+        let (cpu, cycles) = execute(&[0x03], |cpu| cpu.set_bc(0xFFFF));
+        assert_eq!(cpu.get_bc(), 0x0000);
+        assert_eq!(cpu.flags, FIXED);
+        assert_eq!(cycles, 5);
+
+        let (cpu, _) = execute(&[0x13], |cpu| cpu.set_de(0x12FF));
+        assert_eq!(cpu.get_de(), 0x1300);
+
+        let (cpu, _) = execute(&[0x23], |cpu| cpu.set_hl(0x0041));
+        assert_eq!(cpu.get_hl(), 0x0042);
+
+        let (cpu, _) = execute(&[0x33], |cpu| cpu.sp = 0xFFFF);
+        assert_eq!(cpu.sp, 0x0000);
+
+        let (cpu, cycles) = execute(&[0x0B], |cpu| cpu.set_bc(0x0000));
+        assert_eq!(cpu.get_bc(), 0xFFFF);
+        assert_eq!(cpu.flags, FIXED);
+        assert_eq!(cycles, 5);
+
+        let (cpu, _) = execute(&[0x1B], |cpu| cpu.set_de(0x1300));
+        assert_eq!(cpu.get_de(), 0x12FF);
+
+        let (cpu, _) = execute(&[0x2B], |cpu| cpu.set_hl(0x0042));
+        assert_eq!(cpu.get_hl(), 0x0041);
+
+        let (cpu, _) = execute(&[0x3B], |cpu| cpu.sp = 0x0000);
+        assert_eq!(cpu.sp, 0xFFFF);
+    }
+
+    #[test]
+    fn inr_keeps_carry() {
+        let (cpu, cycles) = execute(&[0x3C], |cpu| {
+            cpu.a = 0xFF;
+            cpu.set_flags(CY);
+        });
+        assert_eq!(cpu.a, 0x00);
+        assert_eq!(cpu.flags, FIXED | Z | AC | P | CY);
+        assert_eq!(cycles, 5);
+
+        let (cpu, cycles) = execute(&[0x34], |cpu| {
+            cpu.set_hl(0x2000);
+            cpu.bus.poke_byte(0x2000, 0x0F);
+        });
+        assert_eq!(cpu.bus.peek_byte(0x2000), 0x10);
+        assert_eq!(cpu.flags, FIXED | AC);
+        assert_eq!(cycles, 10);
+    }
+
+    #[test]
+    fn dcr_keeps_carry() {
+        let (cpu, cycles) = execute(&[0x05], |cpu| cpu.set_flags(CY));
+        assert_eq!(cpu.b, 0xFF);
+        assert_eq!(cpu.flags, FIXED | S | P | CY);
+        assert_eq!(cycles, 5);
+
+        let (cpu, _) = execute(&[0x0D], |cpu| cpu.c = 0x01);
+        assert_eq!(cpu.c, 0x00);
+        assert_eq!(cpu.flags, FIXED | Z | AC | P);
+
+        let (cpu, cycles) = execute(&[0x35], |cpu| {
+            cpu.set_hl(0x2000);
+            cpu.bus.poke_byte(0x2000, 0x10);
+        });
+        assert_eq!(cpu.bus.peek_byte(0x2000), 0x0F);
+        assert_eq!(cpu.flags, FIXED | P);
+        assert_eq!(cycles, 10);
+    }
+
+    #[test]
+    fn mvi() {
+        let (cpu, cycles) = execute(&[0x16, 0x42], |_| {});
+        assert_eq!(cpu.d, 0x42);
+        assert_eq!(cpu.pc, 0x0002);
+        assert_eq!(cycles, 7);
+
+        let (cpu, cycles) = execute(&[0x36, 0x43], |cpu| cpu.set_hl(0x2000));
+        assert_eq!(cpu.bus.peek_byte(0x2000), 0x43);
+        assert_eq!(cycles, 10);
+    }
+
+    #[test]
+    fn rotates_only_change_carry() {
+        let (cpu, cycles) = execute(&[0x07], |cpu| {
+            cpu.a = 0x80;
+            cpu.set_flags(S | Z | AC | P);
+        });
+        assert_eq!(cpu.a, 0x01);
+        assert_eq!(cpu.flags, FIXED | S | Z | AC | P | CY);
+        assert_eq!(cycles, 4);
+
+        let (cpu, _) = execute(&[0x0F], |cpu| cpu.a = 0x01);
+        assert_eq!(cpu.a, 0x80);
+        assert_eq!(cpu.flags, FIXED | CY);
+
+        let (cpu, _) = execute(&[0x0F], |cpu| {
+            cpu.a = 0x02;
+            cpu.set_flags(CY);
+        });
+        assert_eq!(cpu.a, 0x01);
+        assert_eq!(cpu.flags, FIXED);
+
+        let (cpu, _) = execute(&[0x17], |cpu| cpu.a = 0x80);
+        assert_eq!(cpu.a, 0x00);
+        assert_eq!(cpu.flags, FIXED | CY);
+
+        let (cpu, _) = execute(&[0x17], |cpu| {
+            cpu.a = 0x01;
+            cpu.set_flags(CY);
+        });
+        assert_eq!(cpu.a, 0x03);
+        assert_eq!(cpu.flags, FIXED);
+
+        let (cpu, _) = execute(&[0x1F], |cpu| cpu.a = 0x01);
+        assert_eq!(cpu.a, 0x00);
+        assert_eq!(cpu.flags, FIXED | CY);
+
+        let (cpu, _) = execute(&[0x1F], |cpu| {
+            cpu.a = 0x00;
+            cpu.set_flags(CY);
+        });
+        assert_eq!(cpu.a, 0x80);
+        assert_eq!(cpu.flags, FIXED);
+    }
+
+    #[test]
+    fn daa() {
+        let (cpu, cycles) = execute(&[0x27], |cpu| cpu.a = 0x9B);
+        assert_eq!(cpu.a, 0x01);
+        assert_eq!(cpu.flags, FIXED | AC | CY);
+        assert_eq!(cycles, 4);
+    }
+
+    #[test]
+    fn carry_and_accumulator_complements() {
+        let (cpu, cycles) = execute(&[0x37], |_| {});
+        assert_eq!(cpu.flags, FIXED | CY);
+        assert_eq!(cycles, 4);
+
+        let (cpu, _) = execute(&[0x3F], |cpu| cpu.set_flags(Z | CY));
+        assert_eq!(cpu.flags, FIXED | Z);
+
+        let (cpu, _) = execute(&[0x3F], |_| {});
+        assert_eq!(cpu.flags, FIXED | CY);
+
+        let (cpu, cycles) = execute(&[0x2F], |cpu| cpu.a = 0x51);
+        assert_eq!(cpu.a, 0xAE);
+        assert_eq!(cpu.flags, FIXED);
+        assert_eq!(cycles, 4);
+    }
+
+    #[test]
+    fn dad_only_changes_carry() {
+        let (cpu, cycles) = execute(&[0x09], |cpu| {
+            cpu.set_hl(0xFFFF);
+            cpu.set_bc(0x0002);
+            cpu.set_flags(Z);
+        });
+        assert_eq!(cpu.get_hl(), 0x0001);
+        assert_eq!(cpu.flags, FIXED | Z | CY);
+        assert_eq!(cycles, 10);
+
+        let (cpu, _) = execute(&[0x19], |cpu| {
+            cpu.set_hl(0x1234);
+            cpu.set_de(0x1111);
+            cpu.set_flags(CY);
+        });
+        assert_eq!(cpu.get_hl(), 0x2345);
+        assert_eq!(cpu.flags, FIXED);
+
+        let (cpu, _) = execute(&[0x29], |cpu| cpu.set_hl(0x1234));
+        assert_eq!(cpu.get_hl(), 0x2468);
+
+        let (cpu, _) = execute(&[0x39], |cpu| {
+            cpu.set_hl(0x0100);
+            cpu.sp = 0x2000;
+        });
+        assert_eq!(cpu.get_hl(), 0x2100);
+    }
+
+    #[test]
+    fn mov() {
+        let (cpu, cycles) = execute(&[0x41], |cpu| cpu.c = 0x42);
+        assert_eq!(cpu.b, 0x42);
+        assert_eq!(cycles, 5);
+
+        let (cpu, cycles) = execute(&[0x77], |cpu| {
+            cpu.a = 0x43;
+            cpu.set_hl(0x2000);
+        });
+        assert_eq!(cpu.bus.peek_byte(0x2000), 0x43);
+        assert_eq!(cycles, 7);
+
+        let (cpu, cycles) = execute(&[0x5E], |cpu| {
+            cpu.set_hl(0x2000);
+            cpu.bus.poke_byte(0x2000, 0x44);
+        });
+        assert_eq!(cpu.e, 0x44);
+        assert_eq!(cycles, 7);
+
+        // Every register to every other one, M excluded
+        let registers = [0, 1, 2, 3, 4, 5, 7];
+        for &dst in &registers {
+            for &src in &registers {
+                let opcode = 0x40 | (dst << 3) | src;
+                let (cpu, _) = execute(&[opcode], |cpu| {
+                    cpu.set_bc(0x0102);
+                    cpu.set_de(0x0304);
+                    cpu.set_hl(0x0506);
+                    cpu.a = 0x07;
+                });
+                let values = [cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l, 0, cpu.a];
+                let expected = if src == 7 { 0x07 } else { src + 1 };
+                assert_eq!(values[dst as usize], expected, "opcode {:02X}", opcode);
+            }
+        }
+    }
+
+    #[test]
+    fn hlt() {
+        let (cpu, cycles) = execute(&[0x76], |_| {});
+        assert!(cpu.halted);
+        assert_eq!(cpu.pc, 0x0001);
+        assert_eq!(cycles, 7);
+    }
+
+    #[test]
+    fn add_and_adc() {
+        let (cpu, cycles) = execute(&[0x80], |cpu| {
+            cpu.a = 0x2E;
+            cpu.b = 0x74;
+        });
+        assert_eq!(cpu.a, 0xA2);
+        assert_eq!(cpu.flags, FIXED | S | AC);
+        assert_eq!(cycles, 4);
+
+        let (cpu, _) = execute(&[0x89], |cpu| {
+            cpu.a = 0x3D;
+            cpu.c = 0x42;
+            cpu.set_flags(CY);
+        });
+        assert_eq!(cpu.a, 0x80);
+        assert_eq!(cpu.flags, FIXED | S | AC);
+
+        let (cpu, cycles) = execute(&[0x86], |cpu| {
+            cpu.a = 0xFF;
+            cpu.set_hl(0x2000);
+            cpu.bus.poke_byte(0x2000, 0x01);
+        });
+        assert_eq!(cpu.a, 0x00);
+        assert_eq!(cpu.flags, FIXED | Z | AC | P | CY);
+        assert_eq!(cycles, 7);
+    }
+
+    #[test]
+    fn sub_and_sbb() {
+        let (cpu, cycles) = execute(&[0x97], |cpu| {
+            cpu.a = 0x3E;
+            cpu.set_flags(CY);
+        });
+        assert_eq!(cpu.a, 0x00);
+        assert_eq!(cpu.flags, FIXED | Z | AC | P);
+        assert_eq!(cycles, 4);
+
+        let (cpu, _) = execute(&[0x90], |cpu| cpu.b = 0x01);
+        assert_eq!(cpu.a, 0xFF);
+        assert_eq!(cpu.flags, FIXED | S | P | CY);
+
+        let (cpu, _) = execute(&[0x9D], |cpu| {
+            cpu.a = 0x04;
+            cpu.l = 0x02;
+            cpu.set_flags(CY);
+        });
+        assert_eq!(cpu.a, 0x01);
+        assert_eq!(cpu.flags, FIXED | AC);
+
+        let (cpu, cycles) = execute(&[0x96], |cpu| {
+            cpu.a = 0x10;
+            cpu.set_hl(0x2000);
+            cpu.bus.poke_byte(0x2000, 0x10);
+        });
+        assert_eq!(cpu.a, 0x00);
+        assert_eq!(cycles, 7);
+    }
+
+    #[test]
+    fn logic() {
+        let (cpu, cycles) = execute(&[0xA1], |cpu| {
+            cpu.a = 0xFC;
+            cpu.c = 0x0F;
+            cpu.set_flags(CY);
+        });
+        assert_eq!(cpu.a, 0x0C);
+        assert_eq!(cpu.flags, FIXED | AC | P);
+        assert_eq!(cycles, 4);
+
+        let (cpu, _) = execute(&[0xAF], |cpu| {
+            cpu.a = 0x5A;
+            cpu.set_flags(AC | CY);
+        });
+        assert_eq!(cpu.a, 0x00);
+        assert_eq!(cpu.flags, FIXED | Z | P);
+
+        let (cpu, _) = execute(&[0xB1], |cpu| {
+            cpu.a = 0x33;
+            cpu.c = 0x0F;
+            cpu.set_flags(AC | CY);
+        });
+        assert_eq!(cpu.a, 0x3F);
+        assert_eq!(cpu.flags, FIXED | P);
+
+        let (cpu, cycles) = execute(&[0xAE], |cpu| {
+            cpu.a = 0xFF;
+            cpu.set_hl(0x2000);
+            cpu.bus.poke_byte(0x2000, 0x0F);
+        });
+        assert_eq!(cpu.a, 0xF0);
+        assert_eq!(cpu.flags, FIXED | S | P);
+        assert_eq!(cycles, 7);
+    }
+
+    #[test]
+    fn cmp_leaves_accumulator() {
+        let (cpu, cycles) = execute(&[0xBB], |cpu| {
+            cpu.a = 0x0A;
+            cpu.e = 0x05;
+        });
+        assert_eq!(cpu.a, 0x0A);
+        assert_eq!(cpu.flags, FIXED | AC | P);
+        assert_eq!(cycles, 4);
+
+        let (cpu, _) = execute(&[0xBB], |cpu| {
+            cpu.a = 0x02;
+            cpu.e = 0x05;
+        });
+        assert_eq!(cpu.a, 0x02);
+        assert_eq!(cpu.flags, FIXED | S | CY);
+
+        let (cpu, cycles) = execute(&[0xBE], |cpu| {
+            cpu.a = 0x42;
+            cpu.set_hl(0x2000);
+            cpu.bus.poke_byte(0x2000, 0x42);
+        });
+        assert_eq!(cpu.flags & (Z | CY), Z);
+        assert_eq!(cycles, 7);
+    }
+
+    #[test]
+    fn immediates() {
+        let cases: [(u8, u8, u8, u8, u8, u8); 8] = [
+            // opcode, operand, A, flags before, A after, flags after
+            (0xC6, 0x42, 0x14, 0, 0x56, FIXED | P),
+            (0xCE, 0xFF, 0x00, CY, 0x00, FIXED | Z | AC | P | CY),
+            (0xD6, 0x01, 0x00, 0, 0xFF, FIXED | S | P | CY),
+            (0xDE, 0x01, 0x00, CY, 0xFE, FIXED | S | CY),
+            (0xE6, 0x0F, 0x3A, CY, 0x0A, FIXED | AC | P),
+            (0xEE, 0x81, 0x3B, CY, 0xBA, FIXED | S),
+            (0xF6, 0x0F, 0xB5, AC | CY, 0xBF, FIXED | S),
+            (0xFE, 0x40, 0x4A, 0, 0x4A, FIXED | AC | P),
+        ];
+        for &(opcode, operand, a, flags, result, result_flags) in &cases {
+            let (cpu, cycles) = execute(&[opcode, operand], |cpu| {
+                cpu.a = a;
+                cpu.set_flags(flags);
+            });
+            assert_eq!(cpu.a, result, "opcode {:02X}", opcode);
+            assert_eq!(cpu.flags, result_flags, "opcode {:02X}", opcode);
+            assert_eq!(cpu.pc, 0x0002);
+            assert_eq!(cycles, 7);
+        }
+    }
+
+    /// Conditional opcodes from the lowest condition bits upwards: NZ, Z, NC, C, PO, PE, P, M
+    const CONDITIONS: [(u8, bool); 8] = [
+        (Z, false),
+        (Z, true),
+        (CY, false),
+        (CY, true),
+        (P, false),
+        (P, true),
+        (S, false),
+        (S, true),
+    ];
+
+    #[test]
+    fn jumps() {
+        let (cpu, cycles) = execute(&[0xC3, 0x00, 0x20], |_| {});
+        assert_eq!(cpu.pc, 0x2000);
+        assert_eq!(cycles, 10);
+
+        for (i, &(flag, expected)) in CONDITIONS.iter().enumerate() {
+            let opcode = 0xC2 | ((i as u8) << 3);
+            for &set in &[false, true] {
+                let (cpu, cycles) = execute(&[opcode, 0x00, 0x20], |cpu| {
+                    cpu.set_flags(if set { flag } else { 0 })
+                });
+                let target = if set == expected { 0x2000 } else { 0x0003 };
+                assert_eq!(cpu.pc, target, "opcode {:02X}", opcode);
+                assert_eq!(cycles, 10);
+            }
+        }
+    }
+
+    #[test]
+    fn calls() {
+        let (cpu, cycles) = execute(&[0xCD, 0x00, 0x20], |cpu| cpu.sp = 0x3000);
+        assert_eq!(cpu.pc, 0x2000);
+        assert_eq!(cpu.sp, 0x2FFE);
+        assert_eq!(cpu.bus.peek_byte(0x2FFE), 0x03);
+        assert_eq!(cpu.bus.peek_byte(0x2FFF), 0x00);
+        assert_eq!(cycles, 17);
+
+        for (i, &(flag, expected)) in CONDITIONS.iter().enumerate() {
+            let opcode = 0xC4 | ((i as u8) << 3);
+            for &set in &[false, true] {
+                let (cpu, cycles) = execute(&[opcode, 0x00, 0x20], |cpu| {
+                    cpu.sp = 0x3000;
+                    cpu.set_flags(if set { flag } else { 0 })
+                });
+                if set == expected {
+                    assert_eq!(cpu.pc, 0x2000, "opcode {:02X}", opcode);
+                    assert_eq!(cpu.sp, 0x2FFE);
+                    assert_eq!(cpu.bus.peek_byte(0x2FFE), 0x03);
+                    assert_eq!(cycles, 17);
+                } else {
+                    assert_eq!(cpu.pc, 0x0003, "opcode {:02X}", opcode);
+                    assert_eq!(cpu.sp, 0x3000);
+                    assert_eq!(cycles, 11);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn returns() {
+        let (cpu, cycles) = execute(&[0xC9], |cpu| {
+            cpu.sp = 0x2FFE;
+            cpu.bus.load_bytes(0x2FFE, &[0x34, 0x12]).unwrap();
+        });
+        assert_eq!(cpu.pc, 0x1234);
+        assert_eq!(cpu.sp, 0x3000);
+        assert_eq!(cycles, 10);
+
+        for (i, &(flag, expected)) in CONDITIONS.iter().enumerate() {
+            let opcode = 0xC0 | ((i as u8) << 3);
+            for &set in &[false, true] {
+                let (cpu, cycles) = execute(&[opcode], |cpu| {
+                    cpu.sp = 0x2FFE;
+                    cpu.bus.load_bytes(0x2FFE, &[0x34, 0x12]).unwrap();
+                    cpu.set_flags(if set { flag } else { 0 })
+                });
+                if set == expected {
+                    assert_eq!(cpu.pc, 0x1234, "opcode {:02X}", opcode);
+                    assert_eq!(cpu.sp, 0x3000);
+                    assert_eq!(cycles, 11);
+                } else {
+                    assert_eq!(cpu.pc, 0x0001, "opcode {:02X}", opcode);
+                    assert_eq!(cpu.sp, 0x2FFE);
+                    assert_eq!(cycles, 5);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rst() {
+        for n in 0..8u8 {
+            let opcode = 0xC7 | (n << 3);
+            let (cpu, cycles) = execute(&[opcode], |cpu| cpu.sp = 0x3000);
+            assert_eq!(cpu.pc, (n as u16) * 8, "opcode {:02X}", opcode);
+            assert_eq!(cpu.sp, 0x2FFE);
+            assert_eq!(cpu.bus.peek_byte(0x2FFE), 0x01);
+            assert_eq!(cpu.bus.peek_byte(0x2FFF), 0x00);
+            assert_eq!(cycles, 11);
+        }
+    }
+
+    #[test]
+    fn push_and_pop() {
+        let (cpu, cycles) = execute(&[0xC5], |cpu| {
+            cpu.set_bc(0x1234);
+            cpu.sp = 0x3000;
+        });
+        assert_eq!(cpu.sp, 0x2FFE);
+        assert_eq!(cpu.bus.peek_byte(0x2FFF), 0x12);
+        assert_eq!(cpu.bus.peek_byte(0x2FFE), 0x34);
+        assert_eq!(cycles, 11);
+
+        let (cpu, _) = execute(&[0xF5], |cpu| {
+            cpu.a = 0x42;
+            cpu.set_flags(0xFF);
+            cpu.sp = 0x3000;
+        });
+        assert_eq!(cpu.bus.peek_byte(0x2FFF), 0x42);
+        assert_eq!(cpu.bus.peek_byte(0x2FFE), S | Z | AC | P | FIXED | CY);
+
+        let (cpu, cycles) = execute(&[0xD1], |cpu| {
+            cpu.sp = 0x2FFE;
+            cpu.bus.load_bytes(0x2FFE, &[0x78, 0x56]).unwrap();
+        });
+        assert_eq!(cpu.get_de(), 0x5678);
+        assert_eq!(cpu.sp, 0x3000);
+        assert_eq!(cycles, 10);
+
+        // The unused flag bits read back fixed no matter what was on the stack
+        let (cpu, _) = execute(&[0xF1], |cpu| {
+            cpu.sp = 0x2FFE;
+            cpu.bus.load_bytes(0x2FFE, &[0xFF, 0x42]).unwrap();
+        });
+        assert_eq!(cpu.a, 0x42);
+        assert_eq!(cpu.flags, S | Z | AC | P | FIXED | CY);
+    }
+
+    #[test]
+    fn exchanges() {
+        let (cpu, cycles) = execute(&[0xE3], |cpu| {
+            cpu.sp = 0x2000;
+            cpu.set_hl(0x0B3C);
+            cpu.bus.load_bytes(0x2000, &[0xF0, 0x0D]).unwrap();
+        });
+        assert_eq!(cpu.get_hl(), 0x0DF0);
+        assert_eq!(cpu.sp, 0x2000);
+        assert_eq!(cpu.bus.peek_byte(0x2000), 0x3C);
+        assert_eq!(cpu.bus.peek_byte(0x2001), 0x0B);
+        assert_eq!(cycles, 18);
+
+        let (cpu, cycles) = execute(&[0xEB], |cpu| {
+            cpu.set_de(0x1234);
+            cpu.set_hl(0x5678);
+        });
+        assert_eq!(cpu.get_de(), 0x5678);
+        assert_eq!(cpu.get_hl(), 0x1234);
+        assert_eq!(cycles, 4);
+    }
+
+    #[test]
+    fn pchl_and_sphl() {
+        let (cpu, cycles) = execute(&[0xE9], |cpu| cpu.set_hl(0x1234));
+        assert_eq!(cpu.pc, 0x1234);
+        assert_eq!(cycles, 5);
+
+        let (cpu, cycles) = execute(&[0xF9], |cpu| cpu.set_hl(0x1234));
+        assert_eq!(cpu.sp, 0x1234);
+        assert_eq!(cpu.pc, 0x0001);
+        assert_eq!(cycles, 5);
+    }
+
+    struct Latch {
+        value: u8,
+        port: u8,
+    }
+
+    impl IoDevice for Latch {
+        fn read(&mut self, port: u8) -> u8 {
+            self.port = port;
+            self.value
+        }
+
+        fn write(&mut self, port: u8, value: u8) {
+            self.port = port;
+            self.value = value;
+        }
+    }
+
+    #[test]
+    fn in_and_out() {
+        let latch = Rc::new(RefCell::new(Latch { value: 0, port: 0 }));
+
+        let device = latch.clone();
+        let (_, cycles) = execute(&[0xD3, 0x10], |cpu| {
+            cpu.a = 0x42;
+            cpu.io.attach(0x10..=0x11, Box::new(device)).unwrap();
+        });
+        assert_eq!(latch.borrow().value, 0x42);
+        assert_eq!(latch.borrow().port, 0x10);
+        assert_eq!(cycles, 10);
+
+        let device = latch.clone();
+        let (cpu, cycles) = execute(&[0xDB, 0x11], |cpu| {
+            cpu.io.attach(0x10..=0x11, Box::new(device)).unwrap();
+        });
+        assert_eq!(cpu.a, 0x42);
+        assert_eq!(latch.borrow().port, 0x11);
+        assert_eq!(cycles, 10);
+
+        let (cpu, _) = execute(&[0xDB, 0x12], |_| {});
+        assert_eq!(cpu.a, 0xFF);
+    }
+
+    #[test]
+    fn ei_and_di() {
+        let (cpu, cycles) = execute(&[0xFB], |_| {});
+        assert!(cpu.inte);
+        assert!(cpu.interrupt_delay);
+        assert_eq!(cycles, 4);
+
+        let (cpu, cycles) = execute(&[0xF3], |cpu| cpu.inte = true);
+        assert!(!cpu.inte);
+        assert_eq!(cycles, 4);
+    }
+
+    /// The word on top of the stack
+    fn stack_top(cpu: &CPU) -> u16 {
+        u16::from_le_bytes([cpu.bus.peek_byte(cpu.sp), cpu.bus.peek_byte(cpu.sp + 1)])
+    }
+
+    #[test]
+    fn interrupt_is_refused_while_inte_is_clear() {
+        let mut cpu = CPU::new();
+        cpu.pc = 0x1234;
+        cpu.sp = 0x2000;
+        let mut executor = Executor::new(&mut cpu);
+        assert_eq!(executor.interrupt(Instruction::Rst(1)), Ok(false));
+        assert_eq!(executor.get_cycles(), 0);
+        assert_eq!((cpu.pc, cpu.sp), (0x1234, 0x2000));
+    }
+
+    #[test]
+    fn interrupt_is_refused_directly_after_ei() {
+        let mut cpu = CPU::new();
+        // EI; NOP; NOP
+        cpu.bus.load_bytes(0, &[0xFB, 0x00, 0x00]).unwrap();
+        cpu.sp = 0x2000;
+        let mut executor = Executor::new(&mut cpu);
+
+        executor.execute().unwrap();
+        assert_eq!(executor.interrupt(Instruction::Rst(1)), Ok(false));
+        executor.execute().unwrap();
+        assert_eq!(executor.interrupt(Instruction::Rst(1)), Ok(true));
+        assert_eq!(cpu.pc, 0x0008);
+        assert_eq!(stack_top(&cpu), 0x0002);
+    }
+
+    #[test]
+    fn rst_interrupt_pushes_the_interrupted_address() {
+        let mut cpu = CPU::new();
+        cpu.pc = 0x1234;
+        cpu.sp = 0x2000;
+        cpu.inte = true;
+        let mut executor = Executor::new(&mut cpu);
+
+        assert_eq!(executor.interrupt(Instruction::Rst(7)), Ok(true));
+        assert_eq!(executor.get_cycles(), 11);
+        assert_eq!((cpu.pc, cpu.sp), (0x0038, 0x1FFE));
+        assert_eq!(stack_top(&cpu), 0x1234);
+        assert!(!cpu.inte);
+
+        // INTE stays clear until the handler enables interrupts again
+        let mut executor = Executor::new(&mut cpu);
+        assert_eq!(executor.interrupt(Instruction::Rst(1)), Ok(false));
+    }
+
+    #[test]
+    fn di_takes_effect_at_once() {
+        let mut cpu = CPU::new();
+        // EI; NOP; DI
+        cpu.bus.load_bytes(0, &[0xFB, 0x00, 0xF3]).unwrap();
+        let mut executor = Executor::new(&mut cpu);
+        executor.run_instructions(3);
+        assert_eq!(executor.interrupt(Instruction::Rst(1)), Ok(false));
+        assert_eq!(cpu.pc, 0x0003);
+    }
+
+    #[test]
+    fn halted_cpu_idles() {
+        let mut cpu = CPU::new();
+        cpu.bus.load_bytes(0, &[0x76]).unwrap();
+        let mut executor = Executor::new(&mut cpu);
+        executor.execute().unwrap();
+        assert_eq!(executor.get_cycles(), 7);
+
+        executor.execute().unwrap();
+        executor.execute().unwrap();
+        assert_eq!(executor.get_cycles(), 7 + 2 * HALT_IDLE_CYCLES);
+        assert_eq!(cpu.pc, 0x0001);
+        assert!(cpu.halted);
+    }
+
+    #[test]
+    fn run_until_halt_stops_at_hlt() {
+        let mut cpu = CPU::new();
+        // MVI A,01h; HLT; INR A
+        cpu.bus.load_bytes(0, &[0x3E, 0x01, 0x76, 0x3C]).unwrap();
+        let mut executor = Executor::new(&mut cpu);
+        assert_eq!(executor.run_until_halt(), StopReason::Halted);
+        // Running again does not leave the HLT
+        assert_eq!(executor.run_until_halt(), StopReason::Halted);
+        assert_eq!(executor.get_cycles(), 14);
+        assert_eq!((cpu.a, cpu.pc), (0x01, 0x0003));
+    }
+
+    #[test]
+    fn interrupt_wakes_a_halted_cpu() {
+        let mut cpu = CPU::new();
+        // EI; HLT; INR A; HLT
+        cpu.bus.load_bytes(0, &[0xFB, 0x76, 0x3C, 0x76]).unwrap();
+        // RST 1 handler: MVI B,05h; RET
+        cpu.bus.load_bytes(0x0008, &[0x06, 0x05, 0xC9]).unwrap();
+        cpu.sp = 0x2000;
+        let mut executor = Executor::new(&mut cpu);
+
+        assert_eq!(executor.run_until_halt(), StopReason::Halted);
+        assert_eq!(executor.interrupt(Instruction::Rst(1)), Ok(true));
+        assert!(!executor.is_halted());
+        assert_eq!(executor.run_until_halt(), StopReason::Halted);
+        assert_eq!((cpu.a, cpu.b, cpu.pc), (0x01, 0x05, 0x0004));
+    }
+
+    #[test]
+    fn halted_cpu_without_inte_stays_halted() {
+        let mut cpu = CPU::new();
+        cpu.bus.load_bytes(0, &[0x76]).unwrap();
+        let mut executor = Executor::new(&mut cpu);
+        executor.run_until_halt();
+
+        assert_eq!(executor.interrupt(Instruction::Rst(1)), Ok(false));
+        assert!(executor.is_halted());
+        assert_eq!(executor.run_cycles(100), StopReason::Halted);
+        assert_eq!(executor.get_cycles(), 107);
+        assert_eq!(cpu.pc, 0x0001);
+    }
+
+    #[test]
+    fn run_cycles_finishes_the_last_instruction() {
+        let mut cpu = CPU::new();
+        // LXI B,0000h three times, 10 cycles each
+        cpu.bus
+            .load_bytes(0, &[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00])
+            .unwrap();
+        let mut executor = Executor::new(&mut cpu);
+        assert_eq!(executor.run_cycles(15), StopReason::BudgetExhausted);
+        // The second instruction started below the budget and runs to completion
+        assert_eq!(executor.get_cycles(), 20);
+        assert_eq!(executor.run_cycles(0), StopReason::BudgetExhausted);
+        assert_eq!(cpu.pc, 0x0006);
+    }
+
+    #[test]
+    fn run_instructions_with_zero_count_does_nothing() {
+        let mut cpu = CPU::new();
+        cpu.bus.load_bytes(0, &[0x3C, 0x3C]).unwrap();
+        let mut executor = Executor::new(&mut cpu);
+        assert_eq!(executor.run_instructions(0), StopReason::BudgetExhausted);
+        assert_eq!(executor.get_cycles(), 0);
+        assert_eq!(executor.run_instructions(2), StopReason::BudgetExhausted);
+        assert_eq!((cpu.a, cpu.pc), (0x02, 0x0002));
+    }
+
+    #[test]
+    fn run_until_executes_before_checking_the_condition() {
+        let mut cpu = CPU::new();
+        cpu.bus.load_bytes(0, &[0x3C, 0x3C, 0x3C]).unwrap();
+        let mut executor = Executor::new(&mut cpu);
+        // Lets a caller continue from a point where the condition holds, like run_com does
+        assert_eq!(executor.run_until(|_| true), StopReason::ConditionMet);
+        assert_eq!(
+            executor.run_until(|cpu| cpu.a == 3),
+            StopReason::ConditionMet
+        );
+        assert_eq!((cpu.a, cpu.pc), (0x03, 0x0003));
+    }
+
+    /// Decoded instructions cannot fail, so errors are checked before all of these
+    /// but can never coincide with them
+    #[test]
+    fn watchpoints_stop_before_breakpoints_before_conditions() {
+        let condition = "A == 42h".parse().unwrap();
+        let run = |watch: bool, breakpoint: bool| {
+            let mut cpu = CPU::new();
+            // MVI A,42h; STA 0040h; NOP
+            cpu.bus
+                .load_bytes(0, &[0x3E, 0x42, 0x32, 0x40, 0x00, 0x00])
+                .unwrap();
+            if watch {
+                cpu.bus
+                    .watchpoints()
+                    .add(Watchpoint::new(0x0040..=0x0040, Access::Write));
+            }
+            let mut executor = Executor::new(&mut cpu);
+            executor.run_instructions(1);
+            if breakpoint {
+                executor.breakpoints_mut().add(0x0005);
+            }
+            executor.breakpoints_mut().add_condition(condition);
+            executor.run_instructions(1)
+        };
+
+        assert_eq!(
+            run(true, true),
+            StopReason::Watchpoint(WatchHit {
+                address: 0x0040,
+                value: 0x42,
+                access: Access::Write
+            })
+        );
+        assert_eq!(run(false, true), StopReason::Breakpoint(0x0005));
+        assert_eq!(run(false, false), StopReason::Condition(condition));
+    }
+
+    /// Cycles per opcode with the branch condition false, from the 8080 data sheet.
+    /// Undocumented opcodes time like the instruction they alias.
+    #[rustfmt::skip]
+    const NOT_TAKEN_CYCLES: [usize; 256] = [
+    //  x0  x1  x2  x3  x4  x5  x6  x7  x8  x9  xA  xB  xC  xD  xE  xF
+         4, 10,  7,  5,  5,  5,  7,  4,  4, 10,  7,  5,  5,  5,  7,  4, // 0x
+         4, 10,  7,  5,  5,  5,  7,  4,  4, 10,  7,  5,  5,  5,  7,  4, // 1x
+         4, 10, 16,  5,  5,  5,  7,  4,  4, 10, 16,  5,  5,  5,  7,  4, // 2x
+         4, 10, 13,  5, 10, 10, 10,  4,  4, 10, 13,  5,  5,  5,  7,  4, // 3x
+         5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5, // 4x
+         5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5, // 5x
+         5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5, // 6x
+         7,  7,  7,  7,  7,  7,  7,  7,  5,  5,  5,  5,  5,  5,  7,  5, // 7x
+         4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 8x
+         4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 9x
+         4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // Ax
+         4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // Bx
+         5, 10, 10, 10, 11, 11,  7, 11,  5, 10, 10, 10, 11, 17,  7, 11, // Cx
+         5, 10, 10, 10, 11, 11,  7, 11,  5, 10, 10, 10, 11, 17,  7, 11, // Dx
+         5, 10, 10, 18, 11, 11,  7, 11,  5,  5, 10,  4, 11, 17,  7, 11, // Ex
+         5, 10, 10,  4, 11, 11,  7, 11,  5,  5, 10,  4, 11, 17,  7, 11, // Fx
+    ];
+
+    /// Cycles per opcode with the branch condition true, only Rcc and Ccc differ
+    #[rustfmt::skip]
+    const TAKEN_CYCLES: [usize; 256] = [
+    //  x0  x1  x2  x3  x4  x5  x6  x7  x8  x9  xA  xB  xC  xD  xE  xF
+         4, 10,  7,  5,  5,  5,  7,  4,  4, 10,  7,  5,  5,  5,  7,  4, // 0x
+         4, 10,  7,  5,  5,  5,  7,  4,  4, 10,  7,  5,  5,  5,  7,  4, // 1x
+         4, 10, 16,  5,  5,  5,  7,  4,  4, 10, 16,  5,  5,  5,  7,  4, // 2x
+         4, 10, 13,  5, 10, 10, 10,  4,  4, 10, 13,  5,  5,  5,  7,  4, // 3x
+         5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5, // 4x
+         5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5, // 5x
+         5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5, // 6x
+         7,  7,  7,  7,  7,  7,  7,  7,  5,  5,  5,  5,  5,  5,  7,  5, // 7x
+         4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 8x
+         4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 9x
+         4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // Ax
+         4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // Bx
+        11, 10, 10, 10, 17, 11,  7, 11, 11, 10, 10, 10, 17, 17,  7, 11, // Cx
+        11, 10, 10, 10, 17, 11,  7, 11, 11, 10, 10, 10, 17, 17,  7, 11, // Dx
+        11, 10, 10, 18, 17, 11,  7, 11, 11,  5, 10,  4, 17, 17,  7, 11, // Ex
+        11, 10, 10,  4, 17, 11,  7, 11, 11,  5, 10,  4, 17, 17,  7, 11, // Fx
+    ];
+
+    #[test]
+    fn cycles_match_timing_table() {
+        for opcode in 0..=0xFFu8 {
+            // RET, JMP and CALL with a condition in bits 3 to 5
+            let conditional = matches!(opcode & 0xC7, 0xC0 | 0xC2 | 0xC4);
+            let (flag, expected) = CONDITIONS[(opcode >> 3 & 0x7) as usize];
+
+            for &taken in &[false, true] {
+                let (_, cycles) = execute(&[opcode, 0x00, 0x20], |cpu| {
+                    cpu.sp = 0x3000;
+                    cpu.set_hl(0x2000);
+                    if conditional {
+                        cpu.set_flags(if taken == expected { flag } else { 0 });
+                    }
+                });
+                let table = if taken && conditional {
+                    &TAKEN_CYCLES
+                } else {
+                    &NOT_TAKEN_CYCLES
+                };
+                assert_eq!(
+                    cycles, table[opcode as usize],
+                    "opcode {:02X} taken {}",
+                    opcode, taken
+                );
             }
-            _ => unimplemented!("[EXECUTOR]: {:?}", instr),
         }
     }
 }
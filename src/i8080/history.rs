@@ -0,0 +1,221 @@
+use super::cpu::*;
+
+use std::collections::VecDeque;
+
+/// A byte written through `Bus::write_byte` while the journal was running
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub address: u16,
+    pub old: u8,
+    pub new: u8,
+}
+
+/// All registers and the interrupt and halt state of the CPU
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RegisterSnapshot {
+    pub a: u8,
+    pub flags: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub inte: bool,
+    pub interrupt_delay: bool,
+    pub halted: bool,
+}
+
+impl RegisterSnapshot {
+    pub fn capture(cpu: &CPU) -> Self {
+        Self {
+            a: cpu.a,
+            flags: cpu.flags,
+            b: cpu.b,
+            c: cpu.c,
+            d: cpu.d,
+            e: cpu.e,
+            h: cpu.h,
+            l: cpu.l,
+            sp: cpu.sp,
+            pc: cpu.pc,
+            inte: cpu.inte,
+            interrupt_delay: cpu.interrupt_delay,
+            halted: cpu.halted,
+        }
+    }
+
+    pub fn restore(&self, cpu: &mut CPU) {
+        cpu.a = self.a;
+        cpu.set_flags(self.flags);
+        cpu.b = self.b;
+        cpu.c = self.c;
+        cpu.d = self.d;
+        cpu.e = self.e;
+        cpu.h = self.h;
+        cpu.l = self.l;
+        cpu.sp = self.sp;
+        cpu.pc = self.pc;
+        cpu.inte = self.inte;
+        cpu.interrupt_delay = self.interrupt_delay;
+        cpu.halted = self.halted;
+    }
+}
+
+/// Everything needed to take back one instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndoRecord {
+    /// State before the instruction
+    pub registers: RegisterSnapshot,
+    pub cycles: usize,
+    /// Memory writes of the instruction in the order they happened
+    pub writes: Vec<MemoryWrite>,
+}
+
+/// Answer to "who last wrote this address"
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LastWrite {
+    /// Address of the instruction that wrote
+    pub pc: u16,
+    /// Cycle counter before that instruction
+    pub cycles: usize,
+    pub old: u8,
+    pub new: u8,
+}
+
+/// Undo records of the most recent instructions, the oldest are dropped once `limit` is reached.
+/// Only CPU and memory state is recorded, I/O devices are not rewound.
+pub struct History {
+    records: VecDeque<UndoRecord>,
+    limit: usize,
+}
+
+impl History {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            records: VecDeque::new(),
+            limit,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    pub fn push(&mut self, record: UndoRecord) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.records.len() == self.limit {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    pub fn pop(&mut self) -> Option<UndoRecord> {
+        self.records.pop_back()
+    }
+
+    /// Finds the most recent recorded write to `address`
+    pub fn last_write(&self, address: u16) -> Option<LastWrite> {
+        self.records.iter().rev().find_map(|record| {
+            record
+                .writes
+                .iter()
+                .rev()
+                .find(|write| write.address == address)
+                .map(|write| LastWrite {
+                    pc: record.registers.pc,
+                    cycles: record.cycles,
+                    old: write.old,
+                    new: write.new,
+                })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::executor::*;
+    use super::*;
+
+    /// An undo record for the instruction at `pc` that made `writes` of `(address, old, new)`
+    fn record(pc: u16, cycles: usize, writes: &[(u16, u8, u8)]) -> UndoRecord {
+        let mut registers = RegisterSnapshot::capture(&CPU::new());
+        registers.pc = pc;
+        UndoRecord {
+            registers,
+            cycles,
+            writes: writes
+                .iter()
+                .map(|&(address, old, new)| MemoryWrite { address, old, new })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn oldest_record_is_dropped_at_the_limit() {
+        let mut history = History::new(2);
+        for cycles in 0..3 {
+            history.push(record(0, cycles, &[]));
+        }
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.pop().map(|r| r.cycles), Some(2));
+        assert_eq!(history.pop().map(|r| r.cycles), Some(1));
+        assert_eq!(history.pop(), None);
+
+        let mut disabled = History::new(0);
+        disabled.push(record(0, 0, &[]));
+        assert!(disabled.is_empty());
+    }
+
+    #[test]
+    fn last_write_finds_the_newest_write() {
+        let mut history = History::new(10);
+        history.push(record(0x0100, 0, &[(0x0040, 0x00, 0x01)]));
+        history.push(record(
+            0x0103,
+            13,
+            &[(0x0040, 0x01, 0x02), (0x0040, 0x02, 0x03)],
+        ));
+        history.push(record(0x0106, 26, &[(0x0041, 0x00, 0x04)]));
+
+        assert_eq!(
+            history.last_write(0x0040),
+            Some(LastWrite {
+                pc: 0x0103,
+                cycles: 13,
+                old: 0x02,
+                new: 0x03
+            })
+        );
+        assert_eq!(history.last_write(0x0042), None);
+    }
+
+    #[test]
+    fn reverse_stops_at_the_start_of_history() {
+        let mut cpu = CPU::new();
+        // MVI A,11h
+        cpu.bus.load_bytes(0, &[0x3E, 0x11]).unwrap();
+        let mut executor = Executor::new(&mut cpu);
+        executor.enable_history(10);
+        assert!(!executor.reverse_step());
+        assert_eq!(executor.reverse_continue(), StopReason::StartOfHistory);
+
+        executor.run_instructions(1);
+        assert!(executor.reverse_step());
+        assert!(!executor.reverse_step());
+        assert_eq!(executor.reverse_continue(), StopReason::StartOfHistory);
+        assert_eq!((executor.cpu().a, executor.cpu().pc), (0x00, 0x0000));
+    }
+}
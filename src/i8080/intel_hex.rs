@@ -0,0 +1,337 @@
+use super::bus::*;
+
+use std::error;
+use std::fmt;
+
+const DATA_RECORD: u8 = 0x00;
+const END_OF_FILE_RECORD: u8 = 0x01;
+const START_SEGMENT_ADDRESS_RECORD: u8 = 0x03;
+
+/// Bytes per data record written by `write_hex`, the usual choice of 8080 tool chains
+pub const DEFAULT_RECORD_SIZE: usize = 16;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HexErrorKind {
+    MissingColon,
+    InvalidDigit,
+    /// The byte count does not match the length of the line
+    LengthMismatch,
+    BadChecksum {
+        expected: u8,
+        found: u8,
+    },
+    UnsupportedRecord(u8),
+    /// The data of a record runs past FFFFh
+    Overflow,
+    MissingEndOfFile,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct HexError {
+    /// 1-based line of the offending record
+    pub line: usize,
+    pub kind: HexErrorKind,
+}
+
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match self.kind {
+            HexErrorKind::MissingColon => write!(f, "record does not start with ':'"),
+            HexErrorKind::InvalidDigit => write!(f, "record contains an invalid hex digit"),
+            HexErrorKind::LengthMismatch => write!(f, "byte count does not match the record"),
+            HexErrorKind::BadChecksum { expected, found } => write!(
+                f,
+                "checksum is {:02X}h but should be {:02X}h",
+                found, expected
+            ),
+            HexErrorKind::UnsupportedRecord(kind) => {
+                write!(f, "record type {:02X}h is not supported", kind)
+            }
+            HexErrorKind::Overflow => write!(f, "data runs past the end of the address space"),
+            HexErrorKind::MissingEndOfFile => write!(f, "end of file record is missing"),
+        }
+    }
+}
+
+impl error::Error for HexError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HexRecord {
+    Data {
+        address: u16,
+        bytes: Vec<u8>,
+    },
+    EndOfFile,
+    /// Segment and offset of a type 03 record combined into one address
+    StartAddress(u16),
+}
+
+/// Parses Intel HEX records of type 00 (data), 01 (end of file) and 03 (start address).
+/// Blank lines are skipped, everything after the end of file record is ignored.
+pub fn parse_hex(source: &str) -> Result<Vec<HexRecord>, HexError> {
+    let mut records = Vec::new();
+    let mut last_line = 0;
+
+    for (i, text) in source.lines().enumerate() {
+        let line = i + 1;
+        last_line = line;
+        let text = text.trim();
+        if text.is_empty() {
+            continue;
+        }
+
+        let record = parse_record(text).map_err(|kind| HexError { line, kind })?;
+        let end_of_file = record == HexRecord::EndOfFile;
+        records.push(record);
+        if end_of_file {
+            return Ok(records);
+        }
+    }
+
+    Err(HexError {
+        line: last_line,
+        kind: HexErrorKind::MissingEndOfFile,
+    })
+}
+
+fn parse_record(text: &str) -> Result<HexRecord, HexErrorKind> {
+    let digits = text.strip_prefix(':').ok_or(HexErrorKind::MissingColon)?;
+    if digits.len() % 2 != 0 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(HexErrorKind::InvalidDigit);
+    }
+
+    let bytes: Vec<u8> = (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+        .collect();
+
+    // Byte count, two address bytes, record type, data and checksum
+    if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+        return Err(HexErrorKind::LengthMismatch);
+    }
+
+    let (body, checksum) = bytes.split_at(bytes.len() - 1);
+    let expected = checksum_of(body);
+    if checksum[0] != expected {
+        return Err(HexErrorKind::BadChecksum {
+            expected,
+            found: checksum[0],
+        });
+    }
+
+    let address = ((body[1] as u16) << 8) | body[2] as u16;
+    let data = &body[4..];
+    match body[3] {
+        DATA_RECORD => {
+            if address as usize + data.len() > MEMORY_SIZE {
+                return Err(HexErrorKind::Overflow);
+            }
+            Ok(HexRecord::Data {
+                address,
+                bytes: data.to_vec(),
+            })
+        }
+        END_OF_FILE_RECORD => Ok(HexRecord::EndOfFile),
+        START_SEGMENT_ADDRESS_RECORD => {
+            if data.len() != 4 {
+                return Err(HexErrorKind::LengthMismatch);
+            }
+            let segment = u16::from_be_bytes([data[0], data[1]]) as usize;
+            let offset = u16::from_be_bytes([data[2], data[3]]) as usize;
+            let start = (segment << 4) + offset;
+            if start >= MEMORY_SIZE {
+                return Err(HexErrorKind::Overflow);
+            }
+            Ok(HexRecord::StartAddress(start as u16))
+        }
+        kind => Err(HexErrorKind::UnsupportedRecord(kind)),
+    }
+}
+
+/// Two's complement of the sum of all bytes
+fn checksum_of(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg()
+}
+
+/// Loads all data records into `bus` and returns where execution starts: the address
+/// of the start address record if there is one, otherwise the address of the first
+/// data record. A file without either starts at 0000h.
+pub fn load_hex(bus: &mut Bus, source: &str) -> Result<u16, HexError> {
+    let mut first_data = None;
+    let mut start = None;
+    for record in parse_hex(source)? {
+        match record {
+            HexRecord::Data { address, bytes } => {
+                // Records were checked against the end of memory while parsing
+                bus.load_bytes(address, &bytes)
+                    .expect("record fits into the address space");
+                first_data.get_or_insert(address);
+            }
+            HexRecord::StartAddress(address) => start = Some(address),
+            HexRecord::EndOfFile => {}
+        }
+    }
+    Ok(start.or(first_data).unwrap_or(0))
+}
+
+/// Dumps `start..=end` as Intel HEX with `record_size` data bytes per record
+pub fn write_hex(bus: &Bus, start: u16, end: u16, record_size: usize) -> String {
+    let mut output = String::new();
+    let mut address = start as usize;
+
+    while address <= end as usize {
+        let length = record_size.clamp(1, 0xFF).min(end as usize + 1 - address);
+        let mut record = vec![
+            length as u8,
+            (address >> 8) as u8,
+            address as u8,
+            DATA_RECORD,
+        ];
+        record.extend((0..length).map(|i| bus.peek_byte((address + i) as u16)));
+        record.push(checksum_of(&record));
+
+        output.push(':');
+        for byte in record {
+            output.push_str(&format!("{:02X}", byte));
+        }
+        output.push('\n');
+        address += length;
+    }
+
+    output.push_str(":00000001FF\n");
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `MVI A,42h` and `HLT` at 0100h
+    const PROGRAM: &str = ":030100003E427606\n:00000001FF\n";
+
+    fn error(source: &str) -> HexError {
+        parse_hex(source).unwrap_err()
+    }
+
+    #[test]
+    fn parses_data_and_end_of_file() {
+        assert_eq!(
+            parse_hex(PROGRAM),
+            Ok(vec![
+                HexRecord::Data {
+                    address: 0x0100,
+                    bytes: vec![0x3E, 0x42, 0x76]
+                },
+                HexRecord::EndOfFile
+            ])
+        );
+    }
+
+    #[test]
+    fn bad_checksum_names_the_line() {
+        let error = error("\n:030100003E427607\n:00000001FF\n");
+        assert_eq!(
+            error,
+            HexError {
+                line: 2,
+                kind: HexErrorKind::BadChecksum {
+                    expected: 0x06,
+                    found: 0x07
+                }
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "line 2: checksum is 07h but should be 06h"
+        );
+    }
+
+    #[test]
+    fn byte_count_must_match_the_record() {
+        assert_eq!(
+            error(":040100003E427606\n").kind,
+            HexErrorKind::LengthMismatch
+        );
+        assert_eq!(error(":0001\n").kind, HexErrorKind::LengthMismatch);
+        // A start address record holds a segment and an offset
+        assert_eq!(
+            error(":020000030000FB\n").kind,
+            HexErrorKind::LengthMismatch
+        );
+    }
+
+    #[test]
+    fn data_past_ffff_overflows() {
+        assert_eq!(
+            error(":02FFFF000102FD\n:00000001FF\n"),
+            HexError {
+                line: 1,
+                kind: HexErrorKind::Overflow
+            }
+        );
+        assert_eq!(error(":0400000310000000E9\n").kind, HexErrorKind::Overflow);
+    }
+
+    #[test]
+    fn end_of_file_record_is_required() {
+        assert_eq!(
+            error(":030100003E427606\n\n"),
+            HexError {
+                line: 2,
+                kind: HexErrorKind::MissingEndOfFile
+            }
+        );
+    }
+
+    #[test]
+    fn other_record_types_are_unsupported() {
+        assert_eq!(
+            error(":020000040000FA\n").kind,
+            HexErrorKind::UnsupportedRecord(0x04)
+        );
+        assert_eq!(
+            error(":00000005FB\n").kind,
+            HexErrorKind::UnsupportedRecord(0x05)
+        );
+    }
+
+    #[test]
+    fn start_address_record_sets_the_start() {
+        let mut bus = Bus::new();
+        assert_eq!(load_hex(&mut bus, PROGRAM), Ok(0x0100));
+
+        let source = format!(":0400000300000120D8\n{}", PROGRAM);
+        assert_eq!(load_hex(&mut bus, &source), Ok(0x0120));
+        assert_eq!(bus.peek_byte(0x0101), 0x42);
+
+        assert_eq!(load_hex(&mut bus, ":00000001FF\n"), Ok(0));
+    }
+
+    #[test]
+    fn written_hex_parses_back() {
+        let mut bus = Bus::new();
+        let data: Vec<u8> = (0..35).map(|i| i * 7).collect();
+        bus.load_bytes(0x0100, &data).unwrap();
+
+        let hex = write_hex(&bus, 0x0100, 0x0122, DEFAULT_RECORD_SIZE);
+        let records = parse_hex(&hex).unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(
+            records[2],
+            HexRecord::Data {
+                address: 0x0120,
+                bytes: data[32..].to_vec()
+            }
+        );
+
+        let mut copy = Bus::new();
+        assert_eq!(load_hex(&mut copy, &hex), Ok(0x0100));
+        for address in 0x00FF..=0x0123 {
+            assert_eq!(copy.peek_byte(address), bus.peek_byte(address));
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use super::breakpoint::*;
+use super::save_state::*;
+use super::trace::*;
+
+use std::cell::RefCell;
+use std::error;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+pub const PORT_COUNT: usize = 0x100;
+
+/// A peripheral on the I/O bus, called for every IN and OUT on its ports
+pub trait IoDevice {
+    fn read(&mut self, port: u8) -> u8;
+    fn write(&mut self, port: u8, value: u8);
+
+    /// Internal state to store in a save state, devices without state save nothing
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Restores the state returned by `save_state`
+    fn load_state(&mut self, _state: &[u8]) -> Result<(), SaveStateError> {
+        Ok(())
+    }
+}
+
+/// Lets the host keep a handle to a device after attaching it
+impl<T: IoDevice> IoDevice for Rc<RefCell<T>> {
+    fn read(&mut self, port: u8) -> u8 {
+        self.borrow_mut().read(port)
+    }
+
+    fn write(&mut self, port: u8, value: u8) {
+        self.borrow_mut().write(port, value)
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.borrow().save_state()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        self.borrow_mut().load_state(state)
+    }
+}
+
+/// Fallback for unclaimed ports: reads return FFh and writes are dropped
+pub struct OpenPorts;
+
+impl IoDevice for OpenPorts {
+    fn read(&mut self, _port: u8) -> u8 {
+        0xFF
+    }
+
+    fn write(&mut self, _port: u8, _value: u8) {}
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IoError {
+    PortInUse(u8),
+}
+
+impl fmt::Display for IoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IoError::PortInUse(port) => write!(f, "port {:02X}h is already claimed", port),
+        }
+    }
+}
+
+impl error::Error for IoError {}
+
+/// The 256 port I/O space addressed by IN and OUT
+pub struct IoBus {
+    devices: Vec<Box<dyn IoDevice>>,
+    ports: [Option<usize>; PORT_COUNT],
+    default: Box<dyn IoDevice>,
+    tracer: Rc<dyn Tracer>,
+    watchpoints: WatchList,
+}
+
+impl IoBus {
+    pub fn new() -> Self {
+        Self {
+            devices: Vec::new(),
+            ports: [None; PORT_COUNT],
+            default: Box::new(OpenPorts),
+            tracer: Rc::new(NullTracer),
+            watchpoints: WatchList::new(),
+        }
+    }
+
+    pub fn set_tracer(&mut self, tracer: Rc<dyn Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Port watchpoints, checked by `read` and `write`
+    pub fn watchpoints(&mut self) -> &mut WatchList {
+        &mut self.watchpoints
+    }
+
+    /// Routes all ports in `ports` to `device`. Fails without changes if any port is taken.
+    pub fn attach(
+        &mut self,
+        ports: RangeInclusive<u8>,
+        device: Box<dyn IoDevice>,
+    ) -> Result<(), IoError> {
+        if let Some(port) = ports
+            .clone()
+            .find(|port| self.ports[*port as usize].is_some())
+        {
+            return Err(IoError::PortInUse(port));
+        }
+
+        self.devices.push(device);
+        for port in ports {
+            self.ports[port as usize] = Some(self.devices.len() - 1);
+        }
+        Ok(())
+    }
+
+    /// Replaces the device that handles all unclaimed ports
+    pub fn set_default(&mut self, device: Box<dyn IoDevice>) {
+        self.default = device;
+    }
+
+    pub fn read(&mut self, port: u8) -> u8 {
+        let result = self.device(port).read(port);
+        self.tracer.trace(&TraceEvent::IoRead {
+            port,
+            value: result,
+        });
+        self.watchpoints.check(port as u16, result, Access::Read);
+        result
+    }
+
+    pub fn write(&mut self, port: u8, value: u8) {
+        self.tracer.trace(&TraceEvent::IoWrite { port, value });
+        self.watchpoints.check(port as u16, value, Access::Write);
+        self.device(port).write(port, value);
+    }
+
+    /// States of all attached devices in attach order, followed by the default device
+    pub fn save_devices(&self) -> Vec<Vec<u8>> {
+        self.devices
+            .iter()
+            .chain(std::iter::once(&self.default))
+            .map(|device| device.save_state())
+            .collect()
+    }
+
+    /// Restores device states returned by `save_devices`
+    pub fn load_devices(&mut self, states: &[Vec<u8>]) -> Result<(), SaveStateError> {
+        let expected = self.devices.len() + 1;
+        if states.len() != expected {
+            return Err(SaveStateError::DeviceCount {
+                expected,
+                found: states.len(),
+            });
+        }
+
+        let devices = self
+            .devices
+            .iter_mut()
+            .chain(std::iter::once(&mut self.default));
+        for (device, state) in devices.zip(states) {
+            device.load_state(state)?;
+        }
+        Ok(())
+    }
+
+    fn device(&mut self, port: u8) -> &mut dyn IoDevice {
+        match self.ports[port as usize] {
+            Some(index) => self.devices[index].as_mut(),
+            None => self.default.as_mut(),
+        }
+    }
+}
+
+impl Default for IoBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers reads with `value` and records every access as (port, value written)
+    struct Recorder {
+        value: u8,
+        writes: Vec<(u8, u8)>,
+    }
+
+    impl Recorder {
+        fn new(value: u8) -> Rc<RefCell<Self>> {
+            Rc::new(RefCell::new(Self {
+                value,
+                writes: Vec::new(),
+            }))
+        }
+    }
+
+    impl IoDevice for Recorder {
+        fn read(&mut self, _port: u8) -> u8 {
+            self.value
+        }
+
+        fn write(&mut self, port: u8, value: u8) {
+            self.writes.push((port, value));
+        }
+    }
+
+    #[test]
+    fn attach_refuses_claimed_ports() {
+        let mut io = IoBus::new();
+        let first = Recorder::new(0x11);
+        let second = Recorder::new(0x22);
+        io.attach(0x10..=0x13, Box::new(first.clone())).unwrap();
+
+        assert_eq!(
+            io.attach(0x00..=0x10, Box::new(second.clone())),
+            Err(IoError::PortInUse(0x10))
+        );
+        assert_eq!(
+            IoError::PortInUse(0x10).to_string(),
+            "port 10h is already claimed"
+        );
+        // The failed attach left the free ports unclaimed
+        assert_eq!(io.read(0x00), 0xFF);
+        assert_eq!(io.read(0x13), 0x11);
+
+        io.attach(0x14..=0x14, Box::new(second)).unwrap();
+        assert_eq!(io.read(0x14), 0x22);
+    }
+
+    #[test]
+    fn unclaimed_ports_are_open() {
+        let mut io = IoBus::new();
+        let device = Recorder::new(0x11);
+        io.attach(0x80..=0x80, Box::new(device.clone())).unwrap();
+
+        for port in &[0x00, 0x7F, 0x81, 0xFF] {
+            io.write(*port, 0x55);
+            assert_eq!(io.read(*port), 0xFF, "port {:02X}h", port);
+        }
+        assert!(device.borrow().writes.is_empty());
+    }
+
+    #[test]
+    fn default_device_takes_unclaimed_ports() {
+        let mut io = IoBus::new();
+        let device = Recorder::new(0x11);
+        let fallback = Recorder::new(0x99);
+        io.attach(0x80..=0x80, Box::new(device.clone())).unwrap();
+        io.set_default(Box::new(fallback.clone()));
+
+        assert_eq!(io.read(0x00), 0x99);
+        assert_eq!(io.read(0x80), 0x11);
+        io.write(0x01, 0xAA);
+        io.write(0x80, 0xBB);
+        assert_eq!(fallback.borrow().writes, [(0x01, 0xAA)]);
+        assert_eq!(device.borrow().writes, [(0x80, 0xBB)]);
+    }
+}
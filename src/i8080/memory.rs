@@ -0,0 +1,122 @@
+use super::bus::MEMORY_SIZE;
+
+/// Anything that can sit on the address bus: RAM, ROM, video memory or peripherals.
+pub trait Memory {
+    fn read(&mut self, address: u16) -> u8;
+
+    /// Reads without side effects, e.g. for the decoder or a debugger.
+    /// Devices whose reads change state should return what `read` would return.
+    /// Instruction fetches go through here, only data accesses use `read` and `write`.
+    fn peek(&self, address: u16) -> u8;
+
+    fn write(&mut self, address: u16, value: u8);
+
+    /// Stores a byte while loading images. Unlike `write` this also fills read-only memory.
+    fn poke(&mut self, address: u16, value: u8) {
+        self.write(address, value);
+    }
+}
+
+/// Flat read/write memory covering the full address space
+pub struct Ram {
+    memory: Vec<u8>,
+}
+
+impl Ram {
+    pub fn new() -> Self {
+        Self {
+            memory: vec![0; MEMORY_SIZE],
+        }
+    }
+}
+
+impl Default for Ram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for Ram {
+    fn read(&mut self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.memory[address as usize] = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::bus::*;
+    use super::super::cpu::*;
+    use super::super::executor::*;
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Access {
+        Read(u16),
+        Write(u16, u8),
+    }
+
+    /// RAM that logs every `read` and `write` and answers reads at 8000h with 5Ah
+    struct Logger {
+        ram: Ram,
+        log: Rc<RefCell<Vec<Access>>>,
+    }
+
+    impl Memory for Logger {
+        fn read(&mut self, address: u16) -> u8 {
+            self.log.borrow_mut().push(Access::Read(address));
+            self.peek(address)
+        }
+
+        fn peek(&self, address: u16) -> u8 {
+            match address {
+                0x8000 => 0x5A,
+                _ => self.ram.peek(address),
+            }
+        }
+
+        fn write(&mut self, address: u16, value: u8) {
+            self.log.borrow_mut().push(Access::Write(address, value));
+            self.ram.write(address, value);
+        }
+
+        fn poke(&mut self, address: u16, value: u8) {
+            self.ram.poke(address, value);
+        }
+    }
+
+    #[test]
+    fn cpu_reads_and_writes_data_through_custom_memory() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let memory = Logger {
+            ram: Ram::new(),
+            log: log.clone(),
+        };
+        let mut cpu = CPU::with_bus(Bus::with_memory(Box::new(memory)));
+        // LDA 8000h; STA 8001h; HLT
+        cpu.bus
+            .load_bytes(0, &[0x3A, 0x00, 0x80, 0x32, 0x01, 0x80, 0x76])
+            .unwrap();
+        // Loading pokes the image in without going through `write`
+        assert!(log.borrow().is_empty());
+
+        Executor::new(&mut cpu).run_until_halt();
+        assert_eq!(cpu.a, 0x5A);
+        assert_eq!(cpu.bus.peek_byte(0x8001), 0x5A);
+        // Fetches are peeks, only the data accesses show up
+        assert_eq!(
+            *log.borrow(),
+            [Access::Read(0x8000), Access::Write(0x8001, 0x5A)]
+        );
+    }
+}
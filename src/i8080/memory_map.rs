@@ -0,0 +1,427 @@
+use super::bus::MEMORY_SIZE;
+use super::memory::*;
+
+use std::error;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RegionKind {
+    Ram,
+    /// Writes are dropped and handed to the map's ROM write handler
+    Rom,
+    /// Nothing is connected, reads return FFh and writes are dropped
+    OpenBus,
+    /// Accesses are redirected to the target range, repeating it if it is shorter
+    Mirror {
+        start: u16,
+        end: u16,
+    },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub start: u16,
+    pub end: u16,
+    pub kind: RegionKind,
+}
+
+impl Region {
+    pub fn contains(&self, address: u16) -> bool {
+        self.start <= address && address <= self.end
+    }
+
+    fn overlaps(&self, other: &Region) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04X}h-{:04X}h  ", self.start, self.end)?;
+        match self.kind {
+            RegionKind::Ram => write!(f, "RAM"),
+            RegionKind::Rom => write!(f, "ROM"),
+            RegionKind::OpenBus => write!(f, "open bus"),
+            RegionKind::Mirror { start, end } => {
+                write!(f, "mirror of {:04X}h-{:04X}h", start, end)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MapError {
+    EmptyRange {
+        start: u16,
+        end: u16,
+    },
+    Overlap(Region, Region),
+    /// The mirror points at addresses that are not backed by RAM or ROM
+    InvalidMirror(Region),
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::EmptyRange { start, end } => {
+                write!(f, "region {:04X}h-{:04X}h is empty", start, end)
+            }
+            MapError::Overlap(first, second) => {
+                write!(f, "regions [{}] and [{}] overlap", first, second)
+            }
+            MapError::InvalidMirror(region) => {
+                write!(f, "region [{}] does not point at RAM or ROM", region)
+            }
+        }
+    }
+}
+
+impl error::Error for MapError {}
+
+/// Declarative description of a memory map, turned into a `MemoryMap` by `build`.
+///
+/// ```
+/// # use i8080_emu::i8080::MemoryMapBuilder;
+/// let map = MemoryMapBuilder::new()
+///     .rom(0x0000..=0x1FFF)
+///     .ram(0x2000..=0x3FFF)
+///     .mirror(0x4000..=0x5FFF, 0x2000..=0x3FFF)
+///     .build()?;
+/// # Ok::<(), i8080_emu::i8080::MapError>(())
+/// ```
+pub struct MemoryMapBuilder {
+    regions: Vec<Region>,
+    errors: Vec<MapError>,
+    on_rom_write: Option<Box<dyn FnMut(u16, u8)>>,
+}
+
+impl MemoryMapBuilder {
+    pub fn new() -> Self {
+        Self {
+            regions: Vec::new(),
+            errors: Vec::new(),
+            on_rom_write: None,
+        }
+    }
+
+    pub fn ram(self, range: RangeInclusive<u16>) -> Self {
+        self.region(range, RegionKind::Ram)
+    }
+
+    pub fn rom(self, range: RangeInclusive<u16>) -> Self {
+        self.region(range, RegionKind::Rom)
+    }
+
+    pub fn open_bus(self, range: RangeInclusive<u16>) -> Self {
+        self.region(range, RegionKind::OpenBus)
+    }
+
+    pub fn mirror(mut self, range: RangeInclusive<u16>, target: RangeInclusive<u16>) -> Self {
+        let (start, end) = target.into_inner();
+        if start > end {
+            self.errors.push(MapError::EmptyRange { start, end });
+            return self;
+        }
+        self.region(range, RegionKind::Mirror { start, end })
+    }
+
+    /// Called with address and value whenever a program writes to ROM
+    pub fn on_rom_write(mut self, handler: impl FnMut(u16, u8) + 'static) -> Self {
+        self.on_rom_write = Some(Box::new(handler));
+        self
+    }
+
+    pub fn build(self) -> Result<MemoryMap, MapError> {
+        if let Some(error) = self.errors.first() {
+            return Err(*error);
+        }
+
+        let mut regions = self.regions;
+        regions.sort_by_key(|region| region.start);
+
+        for pair in regions.windows(2) {
+            if pair[0].overlaps(&pair[1]) {
+                return Err(MapError::Overlap(pair[0], pair[1]));
+            }
+        }
+
+        for region in &regions {
+            if let RegionKind::Mirror { start, end } = region.kind {
+                let covered: u32 = regions
+                    .iter()
+                    .filter(|target| matches!(target.kind, RegionKind::Ram | RegionKind::Rom))
+                    .filter(|target| target.start <= end && start <= target.end)
+                    .map(|target| target.end.min(end) as u32 - target.start.max(start) as u32 + 1)
+                    .sum();
+
+                if covered != end as u32 - start as u32 + 1 {
+                    return Err(MapError::InvalidMirror(*region));
+                }
+            }
+        }
+
+        Ok(MemoryMap {
+            regions,
+            memory: vec![0; MEMORY_SIZE],
+            on_rom_write: self.on_rom_write,
+        })
+    }
+
+    fn region(mut self, range: RangeInclusive<u16>, kind: RegionKind) -> Self {
+        let (start, end) = range.into_inner();
+        if start > end {
+            self.errors.push(MapError::EmptyRange { start, end });
+        } else {
+            self.regions.push(Region { start, end, kind });
+        }
+        self
+    }
+}
+
+impl Default for MemoryMapBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Memory built from a list of regions. Addresses outside of every region behave like open bus.
+pub struct MemoryMap {
+    regions: Vec<Region>,
+    memory: Vec<u8>,
+    on_rom_write: Option<Box<dyn FnMut(u16, u8)>>,
+}
+
+impl MemoryMap {
+    pub fn builder() -> MemoryMapBuilder {
+        MemoryMapBuilder::new()
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// Resolves mirrors and returns the address that is actually accessed and its region kind
+    fn resolve(&self, address: u16) -> (u16, RegionKind) {
+        let region = self.regions.iter().find(|region| region.contains(address));
+        match region.map(|region| (region.start, region.kind)) {
+            Some((region_start, RegionKind::Mirror { start, end })) => {
+                let length = end as u32 - start as u32 + 1;
+                let offset = (address - region_start) as u32 % length;
+                let target = start + offset as u16;
+                (target, self.resolve(target).1)
+            }
+            Some((_, kind)) => (address, kind),
+            None => (address, RegionKind::OpenBus),
+        }
+    }
+}
+
+impl Memory for MemoryMap {
+    fn read(&mut self, address: u16) -> u8 {
+        self.peek(address)
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        match self.resolve(address) {
+            (target, RegionKind::Ram) | (target, RegionKind::Rom) => self.memory[target as usize],
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match self.resolve(address) {
+            (target, RegionKind::Ram) => self.memory[target as usize] = value,
+            (_, RegionKind::Rom) => {
+                if let Some(handler) = self.on_rom_write.as_mut() {
+                    handler(address, value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn poke(&mut self, address: u16, value: u8) {
+        match self.resolve(address) {
+            (target, RegionKind::Ram) | (target, RegionKind::Rom) => {
+                self.memory[target as usize] = value
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for MemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for region in &self.regions {
+            writeln!(f, "{}", region)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn build_error(builder: MemoryMapBuilder) -> MapError {
+        match builder.build() {
+            Ok(_) => panic!("map should not build"),
+            Err(error) => error,
+        }
+    }
+
+    #[test]
+    fn rejects_overlapping_regions() {
+        let error = build_error(
+            MemoryMap::builder()
+                .ram(0x1000..=0x1FFF)
+                .rom(0x0000..=0x1000),
+        );
+        assert_eq!(
+            error,
+            MapError::Overlap(
+                Region {
+                    start: 0x0000,
+                    end: 0x1000,
+                    kind: RegionKind::Rom
+                },
+                Region {
+                    start: 0x1000,
+                    end: 0x1FFF,
+                    kind: RegionKind::Ram
+                }
+            )
+        );
+        assert_eq!(
+            error.to_string(),
+            "regions [0000h-1000h  ROM] and [1000h-1FFFh  RAM] overlap"
+        );
+    }
+
+    #[test]
+    fn rejects_mirrors_of_unbacked_addresses() {
+        let error = build_error(
+            MemoryMap::builder()
+                .ram(0x0000..=0x0FFF)
+                .mirror(0x8000..=0x8FFF, 0x0800..=0x17FF),
+        );
+        let mirror = Region {
+            start: 0x8000,
+            end: 0x8FFF,
+            kind: RegionKind::Mirror {
+                start: 0x0800,
+                end: 0x17FF,
+            },
+        };
+        assert_eq!(error, MapError::InvalidMirror(mirror));
+        assert_eq!(
+            error.to_string(),
+            "region [8000h-8FFFh  mirror of 0800h-17FFh] does not point at RAM or ROM"
+        );
+
+        let error = build_error(
+            MemoryMap::builder()
+                .open_bus(0x0000..=0x0FFF)
+                .mirror(0x8000..=0x8FFF, 0x0000..=0x0FFF),
+        );
+        assert!(matches!(error, MapError::InvalidMirror(_)));
+    }
+
+    #[test]
+    #[allow(clippy::reversed_empty_ranges)]
+    fn rejects_empty_ranges() {
+        let error = build_error(MemoryMap::builder().ram(0x2000..=0x1FFF));
+        assert_eq!(
+            error,
+            MapError::EmptyRange {
+                start: 0x2000,
+                end: 0x1FFF
+            }
+        );
+        assert_eq!(error.to_string(), "region 2000h-1FFFh is empty");
+
+        let error = build_error(
+            MemoryMap::builder()
+                .ram(0x0000..=0x0FFF)
+                .mirror(0x8000..=0x8FFF, 0x0FFF..=0x0000),
+        );
+        assert_eq!(
+            error,
+            MapError::EmptyRange {
+                start: 0x0FFF,
+                end: 0x0000
+            }
+        );
+    }
+
+    #[test]
+    fn rom_writes_go_to_the_handler() {
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let log = writes.clone();
+        let mut map = MemoryMap::builder()
+            .rom(0x0000..=0x0FFF)
+            .mirror(0x1000..=0x1FFF, 0x0000..=0x0FFF)
+            .on_rom_write(move |address, value| log.borrow_mut().push((address, value)))
+            .build()
+            .unwrap();
+        map.poke(0x0010, 0xC3);
+
+        map.write(0x0010, 0x00);
+        map.write(0x1010, 0x01);
+        assert_eq!(map.read(0x0010), 0xC3);
+        assert_eq!(*writes.borrow(), [(0x0010, 0x00), (0x1010, 0x01)]);
+    }
+
+    #[test]
+    fn unmapped_addresses_are_open_bus() {
+        let mut map = MemoryMap::builder()
+            .ram(0x0000..=0x0FFF)
+            .open_bus(0x1000..=0x1FFF)
+            .build()
+            .unwrap();
+        for address in &[0x1000, 0x8000, 0xFFFF] {
+            map.write(*address, 0x00);
+            map.poke(*address, 0x00);
+            assert_eq!(map.read(*address), 0xFF, "{:04X}h", address);
+        }
+    }
+
+    #[test]
+    fn mirrors_repeat_their_target() {
+        let mut map = MemoryMap::builder()
+            .ram(0x2000..=0x23FF)
+            .mirror(0x4000..=0x4FFF, 0x2000..=0x23FF)
+            .build()
+            .unwrap();
+
+        map.write(0x2010, 0x11);
+        assert_eq!(map.read(0x4010), 0x11);
+        assert_eq!(map.read(0x4410), 0x11);
+        assert_eq!(map.read(0x4C10), 0x11);
+
+        map.write(0x4FFF, 0x22);
+        assert_eq!(map.read(0x23FF), 0x22);
+    }
+
+    #[test]
+    fn display_lists_the_regions_in_address_order() {
+        let map = MemoryMap::builder()
+            .ram(0x2000..=0x3FFF)
+            .rom(0x0000..=0x1FFF)
+            .mirror(0x4000..=0x5FFF, 0x2000..=0x3FFF)
+            .open_bus(0x6000..=0xFFFF)
+            .build()
+            .unwrap();
+        assert_eq!(
+            map.to_string(),
+            "0000h-1FFFh  ROM\n\
+             2000h-3FFFh  RAM\n\
+             4000h-5FFFh  mirror of 2000h-3FFFh\n\
+             6000h-FFFFh  open bus\n"
+        );
+    }
+}
@@ -1,11 +1,34 @@
+mod alu;
+mod assembler;
+mod breakpoint;
 mod bus;
+mod cpm;
 mod cpu;
 mod decoder;
+mod disassembler;
 mod executor;
+mod history;
+mod intel_hex;
+mod io;
+mod memory;
+mod memory_map;
+mod save_state;
+mod trace;
 mod util;
 
+pub use assembler::*;
+pub use breakpoint::*;
 pub use bus::*;
+pub use cpm::*;
 pub use cpu::*;
 pub use decoder::*;
+pub use disassembler::*;
 pub use executor::*;
+pub use history::*;
+pub use intel_hex::*;
+pub use io::*;
+pub use memory::*;
+pub use memory_map::*;
+pub use save_state::*;
+pub use trace::*;
 pub use util::*;
@@ -0,0 +1,396 @@
+use super::bus::*;
+use super::executor::*;
+
+use std::error;
+use std::fmt;
+
+const MAGIC: &[u8; 4] = b"I80S";
+
+/// Bumped whenever the layout changes, older or newer states are rejected
+pub const SAVE_STATE_VERSION: u16 = 1;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SaveStateError {
+    /// The data does not start with the save state magic
+    NotASaveState,
+    UnsupportedVersion(u16),
+    Truncated,
+    TrailingData,
+    /// The state was saved with a different set of I/O devices
+    DeviceCount {
+        expected: usize,
+        found: usize,
+    },
+    /// A device could not make sense of its saved state
+    InvalidDeviceState,
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveStateError::NotASaveState => write!(f, "data is not a save state"),
+            SaveStateError::UnsupportedVersion(version) => write!(
+                f,
+                "save state version {} is not supported, expected version {}",
+                version, SAVE_STATE_VERSION
+            ),
+            SaveStateError::Truncated => write!(f, "save state is truncated"),
+            SaveStateError::TrailingData => write!(f, "save state has trailing data"),
+            SaveStateError::DeviceCount { expected, found } => write!(
+                f,
+                "save state holds {} devices but the machine has {}",
+                found, expected
+            ),
+            SaveStateError::InvalidDeviceState => write!(f, "device state is invalid"),
+        }
+    }
+}
+
+impl error::Error for SaveStateError {}
+
+/// Snapshot of a whole machine: registers, interrupt and halt state, the cycle counter,
+/// all 64K of memory and the state of every I/O device.
+///
+/// The binary layout is the magic `I80S` and the version, followed by
+/// A, F, B, C, D, E, H, L, SP, PC, INTE, the EI delay, HLT, the cycle counter,
+/// the memory and the devices as length prefixed blobs. Numbers are little endian.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaveState {
+    registers: [u8; 8],
+    sp: u16,
+    pc: u16,
+    inte: bool,
+    interrupt_delay: bool,
+    halted: bool,
+    cycles: u64,
+    memory: Vec<u8>,
+    devices: Vec<Vec<u8>>,
+}
+
+impl SaveState {
+    pub fn capture(executor: &Executor) -> Self {
+        let cpu = executor.cpu();
+        Self {
+            registers: [cpu.a, cpu.flags, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l],
+            sp: cpu.sp,
+            pc: cpu.pc,
+            inte: cpu.inte,
+            interrupt_delay: cpu.interrupt_delay,
+            halted: cpu.halted,
+            cycles: executor.get_cycles() as u64,
+            memory: (0..MEMORY_SIZE)
+                .map(|address| cpu.bus.peek_byte(address as u16))
+                .collect(),
+            devices: cpu.io.save_devices(),
+        }
+    }
+
+    /// Puts the machine back into the saved state and clears the executor's history,
+    /// whose records would lead back into the replaced machine. The machine has to
+    /// have the same devices attached as when the state was captured.
+    pub fn restore(&self, executor: &mut Executor) -> Result<(), SaveStateError> {
+        executor.cpu_mut().io.load_devices(&self.devices)?;
+        executor.set_cycles(self.cycles as usize);
+
+        let cpu = executor.cpu_mut();
+        let [a, flags, b, c, d, e, h, l] = self.registers;
+        cpu.a = a;
+        cpu.set_flags(flags);
+        cpu.b = b;
+        cpu.c = c;
+        cpu.d = d;
+        cpu.e = e;
+        cpu.h = h;
+        cpu.l = l;
+        cpu.sp = self.sp;
+        cpu.pc = self.pc;
+        cpu.inte = self.inte;
+        cpu.interrupt_delay = self.interrupt_delay;
+        cpu.halted = self.halted;
+        cpu.bus
+            .load_bytes(0, &self.memory)
+            .expect("save state memory covers the address space");
+        executor.clear_history();
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(MEMORY_SIZE + 64);
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&SAVE_STATE_VERSION.to_le_bytes());
+        data.extend_from_slice(&self.registers);
+        data.extend_from_slice(&self.sp.to_le_bytes());
+        data.extend_from_slice(&self.pc.to_le_bytes());
+        data.push(self.inte as u8);
+        data.push(self.interrupt_delay as u8);
+        data.push(self.halted as u8);
+        data.extend_from_slice(&self.cycles.to_le_bytes());
+        data.extend_from_slice(&self.memory);
+
+        data.extend_from_slice(&(self.devices.len() as u32).to_le_bytes());
+        for device in &self.devices {
+            data.extend_from_slice(&(device.len() as u32).to_le_bytes());
+            data.extend_from_slice(device);
+        }
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, SaveStateError> {
+        let mut reader = Reader { data };
+        if reader.bytes(MAGIC.len()) != Ok(&MAGIC[..]) {
+            return Err(SaveStateError::NotASaveState);
+        }
+        let version = reader.u16()?;
+        if version != SAVE_STATE_VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+
+        let mut registers = [0; 8];
+        registers.copy_from_slice(reader.bytes(8)?);
+        let sp = reader.u16()?;
+        let pc = reader.u16()?;
+        let inte = reader.flag()?;
+        let interrupt_delay = reader.flag()?;
+        let halted = reader.flag()?;
+        let cycles = reader.u64()?;
+        let memory = reader.bytes(MEMORY_SIZE)?.to_vec();
+
+        let device_count = reader.u32()?;
+        let mut devices = Vec::new();
+        for _ in 0..device_count {
+            let length = reader.u32()? as usize;
+            devices.push(reader.bytes(length)?.to_vec());
+        }
+
+        if !reader.data.is_empty() {
+            return Err(SaveStateError::TrailingData);
+        }
+
+        Ok(Self {
+            registers,
+            sp,
+            pc,
+            inte,
+            interrupt_delay,
+            halted,
+            cycles,
+            memory,
+            devices,
+        })
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], SaveStateError> {
+        if self.data.len() < count {
+            return Err(SaveStateError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(count);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn flag(&mut self) -> Result<bool, SaveStateError> {
+        Ok(self.bytes(1)?[0] != 0)
+    }
+
+    fn u16(&mut self) -> Result<u16, SaveStateError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, SaveStateError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, SaveStateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::cpu::*;
+    use super::super::io::*;
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Keeps the last byte written to it
+    struct Latch {
+        value: u8,
+    }
+
+    impl IoDevice for Latch {
+        fn read(&mut self, _port: u8) -> u8 {
+            self.value
+        }
+
+        fn write(&mut self, _port: u8, value: u8) {
+            self.value = value;
+        }
+
+        fn save_state(&self) -> Vec<u8> {
+            vec![self.value]
+        }
+
+        fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+            match state {
+                [value] => {
+                    self.value = *value;
+                    Ok(())
+                }
+                _ => Err(SaveStateError::InvalidDeviceState),
+            }
+        }
+    }
+
+    /// MVI A,42h; OUT 10h; INR A; STA 0080h; HLT
+    const PROGRAM: [u8; 10] = [0x3E, 0x42, 0xD3, 0x10, 0x3C, 0x32, 0x80, 0x00, 0x76, 0x00];
+
+    fn machine() -> (CPU, Rc<RefCell<Latch>>) {
+        let latch = Rc::new(RefCell::new(Latch { value: 0 }));
+        let mut cpu = CPU::new();
+        cpu.io.attach(0x10..=0x10, Box::new(latch.clone())).unwrap();
+        cpu.bus.load_bytes(0, &PROGRAM).unwrap();
+        (cpu, latch)
+    }
+
+    /// Bytes of a state captured after the first two instructions of `PROGRAM`
+    fn saved_bytes() -> Vec<u8> {
+        let (mut cpu, _) = machine();
+        let mut executor = Executor::new(&mut cpu);
+        executor.run_instructions(2);
+        SaveState::capture(&executor).to_bytes()
+    }
+
+    #[test]
+    fn round_trip_restores_the_machine() {
+        let (mut cpu, latch) = machine();
+        let mut executor = Executor::new(&mut cpu);
+        executor.run_instructions(2);
+        let cycles = executor.get_cycles();
+
+        let state = SaveState::capture(&executor);
+        let loaded = SaveState::from_bytes(&state.to_bytes()).unwrap();
+        assert_eq!(loaded, state);
+
+        executor.run_until_halt();
+        latch.borrow_mut().value = 0x99;
+        assert_eq!(executor.cpu().bus.peek_byte(0x0080), 0x43);
+
+        loaded.restore(&mut executor).unwrap();
+        assert_eq!(executor.get_cycles(), cycles);
+        assert_eq!(SaveState::capture(&executor), state);
+        let cpu = executor.cpu();
+        assert_eq!((cpu.a, cpu.pc), (0x42, 0x0004));
+        assert!(!cpu.halted);
+        assert_eq!(cpu.bus.peek_byte(0x0080), 0x00);
+        assert_eq!(latch.borrow().value, 0x42);
+    }
+
+    #[test]
+    fn restore_clears_the_history() {
+        let (mut cpu, _) = machine();
+        let mut executor = Executor::new(&mut cpu);
+        executor.enable_history(10);
+        executor.run_instructions(2);
+        let state = SaveState::capture(&executor);
+
+        executor.run_instructions(2);
+        state.restore(&mut executor).unwrap();
+        assert!(!executor.reverse_step());
+        assert_eq!(executor.cpu().pc, 0x0004);
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut data = saved_bytes();
+        data[4..6].copy_from_slice(&(SAVE_STATE_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            SaveState::from_bytes(&data),
+            Err(SaveStateError::UnsupportedVersion(SAVE_STATE_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn rejects_foreign_data() {
+        let mut data = saved_bytes();
+        data[0] = b'X';
+        assert_eq!(
+            SaveState::from_bytes(&data),
+            Err(SaveStateError::NotASaveState)
+        );
+        assert_eq!(
+            SaveState::from_bytes(&[]),
+            Err(SaveStateError::NotASaveState)
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let data = saved_bytes();
+        // Inside the registers, the memory and the device blobs
+        for length in &[10, 1000, data.len() - 1] {
+            assert_eq!(
+                SaveState::from_bytes(&data[..*length]),
+                Err(SaveStateError::Truncated),
+                "length {}",
+                length
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_trailing_data() {
+        let mut data = saved_bytes();
+        data.push(0);
+        assert_eq!(
+            SaveState::from_bytes(&data),
+            Err(SaveStateError::TrailingData)
+        );
+    }
+
+    #[test]
+    fn restore_needs_the_same_devices() {
+        let state = SaveState::from_bytes(&saved_bytes()).unwrap();
+
+        let mut cpu = CPU::new();
+        let mut executor = Executor::new(&mut cpu);
+        assert_eq!(
+            state.restore(&mut executor),
+            Err(SaveStateError::DeviceCount {
+                expected: 1,
+                found: 2
+            })
+        );
+        // Nothing is restored when the devices do not match
+        assert_eq!(executor.get_cycles(), 0);
+        assert_eq!(executor.cpu().pc, 0);
+    }
+
+    #[test]
+    fn restore_checks_device_states() {
+        let mut data = saved_bytes();
+        // The latch blob is the first after the device count, make it two bytes long
+        let blob = data.len() - 4 - 1 - 4;
+        data[blob..blob + 4].copy_from_slice(&2u32.to_le_bytes());
+        data.insert(blob + 5, 0);
+        let state = SaveState::from_bytes(&data).unwrap();
+
+        let (mut cpu, _) = machine();
+        assert_eq!(
+            state.restore(&mut Executor::new(&mut cpu)),
+            Err(SaveStateError::InvalidDeviceState)
+        );
+    }
+}
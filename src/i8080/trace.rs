@@ -0,0 +1,372 @@
+use super::cpu::*;
+use super::decoder::*;
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TraceEvent {
+    BusRead {
+        address: u16,
+        value: u8,
+    },
+    BusWrite {
+        address: u16,
+        value: u8,
+    },
+    IoRead {
+        port: u8,
+        value: u8,
+    },
+    IoWrite {
+        port: u8,
+        value: u8,
+    },
+    Decode {
+        address: u16,
+        instruction: Instruction,
+    },
+    Interrupt {
+        instruction: Instruction,
+    },
+    RegisterRead {
+        register: Register,
+        value: u16,
+    },
+    RegisterWrite {
+        register: Register,
+        value: u16,
+    },
+    /// An instruction finished, `cycles` is what it took
+    Retire {
+        address: u16,
+        instruction: Instruction,
+        cycles: usize,
+    },
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceEvent::BusRead { address, value } => {
+                write!(f, "[BUS]: Reading {:02X}h from {:04X}h", value, address)
+            }
+            TraceEvent::BusWrite { address, value } => {
+                write!(f, "[BUS]: Writing {:02X}h to {:04X}h", value, address)
+            }
+            TraceEvent::IoRead { port, value } => {
+                write!(f, "[IO]: Reading {:02X}h from port {:02X}h", value, port)
+            }
+            TraceEvent::IoWrite { port, value } => {
+                write!(f, "[IO]: Writing {:02X}h to port {:02X}h", value, port)
+            }
+            TraceEvent::Decode {
+                address,
+                instruction,
+            } => write!(
+                f,
+                "[DECODER]: Decoded {:?} at {:04X}h",
+                instruction, address
+            ),
+            TraceEvent::Interrupt { instruction } => {
+                write!(f, "[EXECUTOR]: Accepting interrupt {:?}", instruction)
+            }
+            TraceEvent::RegisterRead { register, value } => {
+                write!(f, "[EXECUTOR]: Reading {:04X}h from {:?}", value, register)
+            }
+            TraceEvent::RegisterWrite { register, value } => {
+                write!(f, "[EXECUTOR]: Writing {:04X}h to {:?}", value, register)
+            }
+            TraceEvent::Retire {
+                address,
+                instruction,
+                cycles,
+            } => write!(
+                f,
+                "[EXECUTOR]: Retired {:?} at {:04X}h after {} cycles",
+                instruction, address, cycles
+            ),
+        }
+    }
+}
+
+/// Receives every trace event. Sinks are shared between bus, I/O bus and
+/// executor, so they take `&self` and use interior mutability if needed.
+pub trait Tracer {
+    fn trace(&self, event: &TraceEvent);
+}
+
+/// Default sink, drops every event
+pub struct NullTracer;
+
+impl Tracer for NullTracer {
+    fn trace(&self, _event: &TraceEvent) {}
+}
+
+/// Prints every event to stdout, one line each
+pub struct StdoutTracer;
+
+impl Tracer for StdoutTracer {
+    fn trace(&self, event: &TraceEvent) {
+        println!("{}", event);
+    }
+}
+
+/// Writes one line per executed instruction, for diffing against other emulators.
+///
+/// ```text
+/// PC: 0100, AF: 0002, BC: 0000, DE: 0000, HL: 0000, SP: 0000, CYC: 0    (31 00 02)    LXI SP,0200h
+/// ```
+///
+/// The register part follows the format printed by common 8080 reference emulators:
+/// registers and the cumulative cycle count are taken before the instruction runs.
+/// The instruction bytes and the disassembly follow, each preceded by a tab.
+pub struct InstructionTrace {
+    writer: Box<dyn Write>,
+    ranges: Vec<RangeInclusive<u16>>,
+    error: Option<io::Error>,
+}
+
+impl InstructionTrace {
+    pub fn new(writer: Box<dyn Write>) -> Self {
+        Self {
+            writer,
+            ranges: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn to_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Self::new(Box::new(BufWriter::new(file))))
+    }
+
+    /// Only traces instructions whose PC lies in one of the added ranges.
+    /// Without any range every instruction is traced.
+    pub fn with_range(mut self, range: RangeInclusive<u16>) -> Self {
+        self.ranges.push(range);
+        self
+    }
+
+    pub fn record(&mut self, cpu: &CPU, cycles: usize, instruction: Instruction) {
+        if self.error.is_some() {
+            return;
+        }
+        if !self.ranges.is_empty() && !self.ranges.iter().any(|r| r.contains(&cpu.pc)) {
+            return;
+        }
+
+        let bytes: Vec<String> = (0..instruction.size())
+            .map(|i| format!("{:02X}", cpu.bus.peek_byte(cpu.pc.wrapping_add(i))))
+            .collect();
+        let result = writeln!(
+            self.writer,
+            "PC: {:04X}, AF: {:04X}, BC: {:04X}, DE: {:04X}, HL: {:04X}, SP: {:04X}, CYC: {}\t({})\t{}",
+            cpu.pc,
+            cpu.get_psw(),
+            cpu.get_bc(),
+            cpu.get_de(),
+            cpu.get_hl(),
+            cpu.sp,
+            cycles,
+            bytes.join(" "),
+            instruction
+        );
+        if let Err(error) = result {
+            self.error = Some(error);
+        }
+    }
+
+    /// Flushes the writer and reports the first error that happened while tracing
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => self.writer.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::executor::*;
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: RefCell<Vec<TraceEvent>>,
+    }
+
+    impl Tracer for Recorder {
+        fn trace(&self, event: &TraceEvent) {
+            self.events.borrow_mut().push(*event);
+        }
+    }
+
+    /// MVI A,42h; OUT 10h; STA 0040h; HLT
+    const PROGRAM: [u8; 8] = [0x3E, 0x42, 0xD3, 0x10, 0x32, 0x40, 0x00, 0x76];
+
+    #[test]
+    fn installed_tracer_sees_bus_io_and_register_events() {
+        let recorder = Rc::new(Recorder::default());
+        let mut cpu = CPU::new();
+        cpu.bus.load_bytes(0, &PROGRAM).unwrap();
+        cpu.set_tracer(recorder.clone());
+        Executor::new(&mut cpu).run_until_halt();
+
+        let events = recorder.events.borrow();
+        for expected in &[
+            TraceEvent::Decode {
+                address: 0x0000,
+                instruction: Instruction::Mvi(Register::A, 0x42),
+            },
+            TraceEvent::RegisterWrite {
+                register: Register::A,
+                value: 0x42,
+            },
+            TraceEvent::IoWrite {
+                port: 0x10,
+                value: 0x42,
+            },
+            TraceEvent::BusWrite {
+                address: 0x0040,
+                value: 0x42,
+            },
+            TraceEvent::Retire {
+                address: 0x0007,
+                instruction: Instruction::Hlt,
+                cycles: 7,
+            },
+        ] {
+            assert!(events.contains(expected), "missing {}", expected);
+        }
+        // Fetches are side effect free and do not show up as bus reads
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, TraceEvent::BusRead { .. })));
+    }
+
+    #[test]
+    fn events_print_as_one_line_each() {
+        let lines: Vec<String> = [
+            TraceEvent::BusRead {
+                address: 0x0040,
+                value: 0x42,
+            },
+            TraceEvent::IoWrite {
+                port: 0x10,
+                value: 0x0A,
+            },
+            TraceEvent::RegisterWrite {
+                register: Register::SP,
+                value: 0x0200,
+            },
+        ]
+        .iter()
+        .map(TraceEvent::to_string)
+        .collect();
+        assert_eq!(
+            lines,
+            [
+                "[BUS]: Reading 42h from 0040h",
+                "[IO]: Writing 0Ah to port 10h",
+                "[EXECUTOR]: Writing 0200h to SP",
+            ]
+        );
+    }
+
+    /// Writer the test keeps a handle to
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Traces `program` at 0100h until it halts and returns the trace
+    fn trace(program: &[u8], ranges: &[RangeInclusive<u16>]) -> String {
+        let output = Shared::default();
+        let mut trace = InstructionTrace::new(Box::new(output.clone()));
+        for range in ranges {
+            trace = trace.with_range(range.clone());
+        }
+
+        let mut cpu = CPU::new();
+        cpu.bus.load_bytes(0x0100, program).unwrap();
+        cpu.pc = 0x0100;
+        let mut executor = Executor::new(&mut cpu);
+        executor.set_instruction_trace(trace);
+        executor.run_until_halt();
+        executor.take_instruction_trace().unwrap().finish().unwrap();
+
+        let text = String::from_utf8(output.0.borrow().clone()).unwrap();
+        text
+    }
+
+    /// LXI SP,0200h; MVI A,05h; PUSH PSW; HLT
+    const TRACED: [u8; 7] = [0x31, 0x00, 0x02, 0x3E, 0x05, 0xF5, 0x76];
+
+    #[test]
+    fn instruction_trace_writes_registers_before_each_instruction() {
+        assert_eq!(
+            trace(&TRACED, &[]),
+            "PC: 0100, AF: 0002, BC: 0000, DE: 0000, HL: 0000, SP: 0000, CYC: 0\t(31 00 02)\tLXI SP,0200h\n\
+             PC: 0103, AF: 0002, BC: 0000, DE: 0000, HL: 0000, SP: 0200, CYC: 10\t(3E 05)\tMVI A,05h\n\
+             PC: 0105, AF: 0502, BC: 0000, DE: 0000, HL: 0000, SP: 0200, CYC: 17\t(F5)\tPUSH PSW\n\
+             PC: 0106, AF: 0502, BC: 0000, DE: 0000, HL: 0000, SP: 01FE, CYC: 28\t(76)\tHLT\n"
+        );
+    }
+
+    #[test]
+    fn instruction_trace_only_writes_instructions_in_range() {
+        let lines = |ranges: &[RangeInclusive<u16>]| -> Vec<String> {
+            trace(&TRACED, ranges)
+                .lines()
+                .map(|line| line[..8].to_string())
+                .collect()
+        };
+        assert_eq!(lines(&[0x0103..=0x0105]), ["PC: 0103", "PC: 0105"]);
+        assert_eq!(
+            lines(&[0x0100..=0x0100, 0x0106..=0xFFFF]),
+            ["PC: 0100", "PC: 0106"]
+        );
+        assert!(lines(&[0x0000..=0x00FF]).is_empty());
+    }
+
+    #[test]
+    fn instruction_trace_reports_write_errors() {
+        struct Broken;
+
+        impl Write for Broken {
+            fn write(&mut self, _data: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut cpu = CPU::new();
+        let mut trace = InstructionTrace::new(Box::new(Broken));
+        trace.record(&cpu, 0, Instruction::Nop);
+        cpu.pc = 1;
+        trace.record(&cpu, 4, Instruction::Nop);
+        assert_eq!(
+            trace.finish().unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+}
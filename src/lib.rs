@@ -0,0 +1,3 @@
+pub mod gdb;
+pub mod i8080;
+pub mod monitor;
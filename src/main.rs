@@ -1,24 +1,72 @@
-#[allow(dead_code)]
-mod i8080;
 // CPU Frequency:      2 MHZ
 // Data Bus:           8 Bit
 // Address Bus:        16 Bit
 // Addressable memory: 64 KB
 // Addressable IO:     256 B
 
+use i8080_emu::{gdb, i8080, monitor};
+
+use std::env;
+use std::io;
+use std::path::Path;
+use std::process;
+
+/// Port `i8080_emu gdb` listens on unless another one is given
+const DEFAULT_GDB_PORT: u16 = 1234;
+
+const USAGE: &str = "usage: i8080_emu debug <file>
+       i8080_emu gdb <file> [port]";
+
 fn main() {
-    let mut cpu = i8080::CPU::new();
+    let args: Vec<String> = env::args().collect();
 
     println!("[*] Welcome to the i8080_emu Emulator Project");
 
-    cpu.set_bc(0xDEAD);
-    cpu.a = 0xFF;
-    cpu.bus.write_byte(0x00, 0x02);
+    match args.get(1).map(String::as_str) {
+        Some("debug") if args.len() == 3 => debug(Path::new(&args[2])),
+        Some("gdb") if args.len() == 3 => gdb(Path::new(&args[2]), DEFAULT_GDB_PORT),
+        Some("gdb") if args.len() == 4 => match args[3].parse() {
+            Ok(port) => gdb(Path::new(&args[2]), port),
+            Err(_) => {
+                eprintln!("[!] Invalid port {}", args[3]);
+                process::exit(2);
+            }
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+}
+
+fn load(path: &Path) -> i8080::CPU {
+    let mut cpu = i8080::CPU::new();
+    if let Err(error) = monitor::load_program(&mut cpu, path) {
+        eprintln!("[!] Could not load {}: {}", path.display(), error);
+        process::exit(1);
+    }
+    cpu
+}
 
-    {
-        let mut executor = i8080::Executor::new(&mut cpu);
-        executor.execute();
+fn debug(path: &Path) {
+    let mut cpu = load(path);
+    let executor = i8080::Executor::new(&mut cpu);
+    let stdin = io::stdin();
+    let mut monitor = monitor::Monitor::new(executor);
+    if let Err(error) = monitor.run(stdin.lock(), &mut io::stdout()) {
+        eprintln!("[!] {}", error);
+        process::exit(1);
     }
+}
+
+fn gdb(path: &Path, port: u16) {
+    let mut cpu = load(path);
+    let executor = i8080::Executor::new(&mut cpu);
 
-    assert_eq!(cpu.bus.read_byte(0xDEAD), 0xFF);
+    println!("[*] Waiting for GDB on 127.0.0.1:{}", port);
+    let mut server = gdb::GdbServer::new(executor);
+    if let Err(error) = server.listen(port) {
+        eprintln!("[!] {}", error);
+        process::exit(1);
+    }
 }
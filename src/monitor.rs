@@ -0,0 +1,789 @@
+use crate::i8080::*;
+
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+/// Bytes shown by a `D` command without an end address
+const DUMP_LENGTH: u16 = 0x80;
+const DUMP_LINE: u16 = 0x10;
+/// Instructions shown by an `L` command without a count
+const LIST_LENGTH: usize = 12;
+/// How many bytes before PC `L` looks for instructions leading up to it
+const LIST_LOOKBEHIND: u16 = 8;
+/// Instructions that `R` and `V` can take back
+const HISTORY_LENGTH: usize = 100_000;
+/// Instructions a `G` runs at most, so that a program that never halts hands control back
+const GO_LIMIT: usize = 10_000_000;
+
+const HELP: &str = "\
+T [n]                trace n instructions (default 1)
+G [addr]             go from PC or addr until a breakpoint, watchpoint or HLT,
+                     at most 10 million instructions
+R [n]                take back n instructions (default 1)
+V                    go backwards until a breakpoint or condition
+B [addr [cond]]      set a breakpoint at addr that stops while cond holds,
+                     or list breakpoints, watchpoints and conditions
+W start [end] [R|W]  watch reads and/or writes of memory
+P port [end] [R|W]   watch IN and/or OUT on I/O ports
+U cond               stop wherever cond holds
+C [addr]             clear breakpoints and watchpoints at addr, or all of them
+Y addr               show which instruction last wrote addr
+X [reg value]        show registers or set A F B C D E H L BC DE HL SP PC PSW
+D [start [end]]      dump memory
+S addr bytes...      substitute bytes starting at addr
+L [addr [n]]         list n instructions at addr, or around PC
+Z                    reset the cycle counter
+> file               save the machine state to file
+< file               restore the machine state from file
+H                    show this help
+Q                    quit
+Numbers are hexadecimal. Conditions compare a register with a decimal
+value, or a hex value written as 0x2400 or 2400h, e.g. HL == 0x2400.
+Flags are shown as C carry, Z zero, M sign, E even parity and
+I auxiliary carry.";
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Hex(HexError),
+    Bus(BusError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(error) => write!(f, "{}", error),
+            LoadError::Hex(error) => write!(f, "invalid hex file, {}", error),
+            LoadError::Bus(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl error::Error for LoadError {}
+
+/// Loads `.hex` files as Intel HEX, `.com` files as CP/M programs and
+/// everything else as a raw binary at 0000h. PC is set to the entry point.
+pub fn load_program(cpu: &mut CPU, path: &Path) -> Result<(), LoadError> {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("hex") | Some("ihx") => {
+            let source = fs::read_to_string(path).map_err(LoadError::Io)?;
+            cpu.pc = load_hex(&mut cpu.bus, &source).map_err(LoadError::Hex)?;
+        }
+        Some("com") => {
+            let program = fs::read(path).map_err(LoadError::Io)?;
+            load_com(cpu, &program).map_err(LoadError::Bus)?;
+        }
+        _ => {
+            let program = fs::read(path).map_err(LoadError::Io)?;
+            cpu.bus.load_bytes(0, &program).map_err(LoadError::Bus)?;
+            cpu.pc = 0;
+        }
+    }
+    Ok(())
+}
+
+/// Interactive debugger with single letter commands in the style of CP/M's DDT and SID
+pub struct Monitor<'a> {
+    executor: Executor<'a>,
+    disassembler: Disassembler,
+    /// Where `D` without an address continues
+    next_dump: u16,
+    /// Instructions a `G` runs at most
+    go_limit: usize,
+}
+
+impl<'a> Monitor<'a> {
+    pub fn new(mut executor: Executor<'a>) -> Self {
+        executor.enable_history(HISTORY_LENGTH);
+        let next_dump = executor.cpu().pc;
+        Self {
+            executor,
+            disassembler: Disassembler::new(HexStyle::Intel),
+            next_dump,
+            go_limit: GO_LIMIT,
+        }
+    }
+
+    /// Reads commands from `input` until `Q` or the end of the input
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, output: &mut W) -> io::Result<()> {
+        self.show_registers(output)?;
+        write!(output, "-")?;
+        output.flush()?;
+
+        for line in input.lines() {
+            if !self.command(&line?, output)? {
+                return Ok(());
+            }
+            write!(output, "-")?;
+            output.flush()?;
+        }
+        writeln!(output)
+    }
+
+    /// Executes a single command line, returns false once the user quits
+    pub fn command<W: Write>(&mut self, line: &str, output: &mut W) -> io::Result<bool> {
+        let line = line.trim();
+        let mut chars = line.chars();
+        let command = match chars.next() {
+            Some(command) => command.to_ascii_uppercase(),
+            None => return Ok(true),
+        };
+        let rest = chars.as_str().trim();
+        let args: Vec<&str> = rest
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|arg| !arg.is_empty())
+            .collect();
+
+        let known = match command {
+            'T' => self.trace(&args, output)?,
+            'G' => self.go(&args, output)?,
+            'R' => self.reverse_trace(&args, output)?,
+            'V' if args.is_empty() => {
+                let reason = self.executor.reverse_continue();
+                self.report(reason, output)?;
+                true
+            }
+            'Y' => self.last_write(&args, output)?,
+            'B' => self.set_breakpoint(&args, output)?,
+            'W' => self.watch(&args, false),
+            'P' => self.watch(&args, true),
+            'U' => self.until(&args),
+            'C' => self.clear_breakpoint(&args),
+            'X' => self.examine(&args, output)?,
+            'D' => self.dump(&args, output)?,
+            'S' => self.substitute(&args),
+            'L' => self.list(&args, output)?,
+            '>' if !rest.is_empty() => self.save_state(rest, output)?,
+            '<' if !rest.is_empty() => self.load_state(rest, output)?,
+            'Z' if args.is_empty() => {
+                self.executor.reset_cycles();
+                true
+            }
+            'H' | '?' => {
+                writeln!(output, "{}", HELP)?;
+                true
+            }
+            'Q' => return Ok(false),
+            _ => false,
+        };
+
+        if !known {
+            // DDT answers everything it does not understand with a question mark
+            writeln!(output, "?")?;
+        }
+        Ok(true)
+    }
+
+    fn trace<W: Write>(&mut self, args: &[&str], output: &mut W) -> io::Result<bool> {
+        let count = match args {
+            [] => 1,
+            [count] => match parse_number(count) {
+                Some(count) if count > 0 => count,
+                _ => return Ok(false),
+            },
+            _ => return Ok(false),
+        };
+
+        for _ in 0..count {
+            let reason = self.executor.run_instructions(1);
+            if reason != StopReason::BudgetExhausted {
+                self.report(reason, output)?;
+                break;
+            }
+            self.show_registers(output)?;
+        }
+        Ok(true)
+    }
+
+    fn go<W: Write>(&mut self, args: &[&str], output: &mut W) -> io::Result<bool> {
+        match args {
+            [] => {}
+            [address] => match parse_number(address) {
+                Some(address) => {
+                    self.executor.cpu_mut().pc = address;
+                    self.executor.clear_history();
+                }
+                None => return Ok(false),
+            },
+            _ => return Ok(false),
+        }
+
+        let reason = self.executor.run_instructions(self.go_limit);
+        if reason == StopReason::BudgetExhausted {
+            writeln!(output, "still running after {} instructions", self.go_limit)?;
+        }
+        self.report(reason, output)?;
+        Ok(true)
+    }
+
+    fn reverse_trace<W: Write>(&mut self, args: &[&str], output: &mut W) -> io::Result<bool> {
+        let count = match args {
+            [] => 1,
+            [count] => match parse_number(count) {
+                Some(count) if count > 0 => count,
+                _ => return Ok(false),
+            },
+            _ => return Ok(false),
+        };
+
+        for _ in 0..count {
+            if !self.executor.reverse_step() {
+                self.report(StopReason::StartOfHistory, output)?;
+                break;
+            }
+            self.show_registers(output)?;
+        }
+        Ok(true)
+    }
+
+    fn last_write<W: Write>(&mut self, args: &[&str], output: &mut W) -> io::Result<bool> {
+        let address = match args {
+            [address] => match parse_number(address) {
+                Some(address) => address,
+                None => return Ok(false),
+            },
+            _ => return Ok(false),
+        };
+
+        match self.executor.last_write(address) {
+            Some(write) => writeln!(
+                output,
+                "{:04X} written by {:04X} at cycle {}, {:02X} -> {:02X}",
+                address, write.pc, write.cycles, write.old, write.new
+            )?,
+            None => writeln!(output, "no recorded write to {:04X}", address)?,
+        }
+        Ok(true)
+    }
+
+    fn report<W: Write>(&mut self, reason: StopReason, output: &mut W) -> io::Result<()> {
+        let pc = self.executor.cpu().pc;
+        match reason {
+            StopReason::Breakpoint(address) => writeln!(output, "*{:04X}", address)?,
+            StopReason::Watchpoint(hit) if hit.access == Access::Write => writeln!(
+                output,
+                "*{:04X} wrote {:02X}h to {:04X}h",
+                pc, hit.value, hit.address
+            )?,
+            StopReason::Watchpoint(hit) => writeln!(
+                output,
+                "*{:04X} read {:02X}h from {:04X}h",
+                pc, hit.value, hit.address
+            )?,
+            StopReason::PortWatchpoint(hit) if hit.access == Access::Write => writeln!(
+                output,
+                "*{:04X} OUT {:02X}h to port {:02X}h",
+                pc, hit.value, hit.address
+            )?,
+            StopReason::PortWatchpoint(hit) => writeln!(
+                output,
+                "*{:04X} IN {:02X}h from port {:02X}h",
+                pc, hit.value, hit.address
+            )?,
+            StopReason::Condition(condition) => writeln!(output, "*{:04X} {}", pc, condition)?,
+            StopReason::Halted => writeln!(output, "halted")?,
+            StopReason::StartOfHistory => writeln!(output, "start of history")?,
+            StopReason::Error(error) => writeln!(output, "error: {}", error)?,
+            StopReason::BudgetExhausted | StopReason::ConditionMet => {}
+        }
+        self.show_registers(output)
+    }
+
+    fn set_breakpoint<W: Write>(&mut self, args: &[&str], output: &mut W) -> io::Result<bool> {
+        let (address, condition) = match args {
+            [] => {
+                self.list_breakpoints(output)?;
+                return Ok(true);
+            }
+            [address, condition @ ..] => (parse_number(address), condition.join(" ")),
+        };
+        let address = match address {
+            Some(address) => address,
+            None => return Ok(false),
+        };
+
+        if condition.is_empty() {
+            self.executor.breakpoints_mut().add(address);
+            return Ok(true);
+        }
+        match condition.parse() {
+            Ok(condition) => {
+                self.executor
+                    .breakpoints_mut()
+                    .add_conditional(address, condition);
+            }
+            Err(error) => writeln!(output, "{}", error)?,
+        }
+        Ok(true)
+    }
+
+    fn list_breakpoints<W: Write>(&mut self, output: &mut W) -> io::Result<()> {
+        for (address, condition) in self.executor.breakpoints().breakpoints() {
+            match condition {
+                Some(condition) => writeln!(output, "{:04X} if {}", address, condition)?,
+                None => writeln!(output, "{:04X}", address)?,
+            }
+        }
+        for watchpoint in self.executor.cpu_mut().bus.watchpoints().watchpoints() {
+            writeln!(
+                output,
+                "W {:04X}-{:04X} {}",
+                watchpoint.start,
+                watchpoint.end,
+                access_name(watchpoint.access)
+            )?;
+        }
+        for watchpoint in self.executor.cpu_mut().io.watchpoints().watchpoints() {
+            writeln!(
+                output,
+                "P {:02X}-{:02X} {}",
+                watchpoint.start,
+                watchpoint.end,
+                access_name(watchpoint.access)
+            )?;
+        }
+        for condition in self.executor.breakpoints().conditions() {
+            writeln!(output, "U {}", condition)?;
+        }
+        Ok(())
+    }
+
+    /// Handles `W` for memory and `P` for I/O ports
+    fn watch(&mut self, args: &[&str], ports: bool) -> bool {
+        let (access, range) = match args.split_last() {
+            Some((last, range)) => match last.to_ascii_uppercase().as_str() {
+                "R" => (Access::Read, range),
+                "W" => (Access::Write, range),
+                "RW" => (Access::ReadWrite, range),
+                _ => (Access::ReadWrite, args),
+            },
+            None => return false,
+        };
+
+        let limit = if ports { 0xFF } else { 0xFFFF };
+        let (start, end) = match parse_numbers(range).as_deref() {
+            Some([start]) => (*start, *start),
+            Some([start, end]) => (*start, *end),
+            _ => return false,
+        };
+        if start > end || end > limit {
+            return false;
+        }
+
+        let watchpoint = Watchpoint::new(start..=end, access);
+        let cpu = self.executor.cpu_mut();
+        if ports {
+            cpu.io.watchpoints().add(watchpoint);
+        } else {
+            cpu.bus.watchpoints().add(watchpoint);
+        }
+        true
+    }
+
+    fn until(&mut self, args: &[&str]) -> bool {
+        match args.join(" ").parse() {
+            Ok(condition) => {
+                self.executor.breakpoints_mut().add_condition(condition);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn clear_breakpoint(&mut self, args: &[&str]) -> bool {
+        let address = match args {
+            [] => {
+                self.executor.breakpoints_mut().clear();
+                self.executor.cpu_mut().bus.watchpoints().clear();
+                self.executor.cpu_mut().io.watchpoints().clear();
+                return true;
+            }
+            [address] => match parse_number(address) {
+                Some(address) => address,
+                None => return false,
+            },
+            _ => return false,
+        };
+
+        let mut removed = self.executor.breakpoints_mut().remove(address);
+        let cpu = self.executor.cpu_mut();
+        for watches in [cpu.bus.watchpoints(), cpu.io.watchpoints()] {
+            let matching: Vec<Watchpoint> = watches
+                .watchpoints()
+                .iter()
+                .copied()
+                .filter(|watchpoint| watchpoint.start == address)
+                .collect();
+            for watchpoint in matching {
+                removed |= watches.remove(&watchpoint);
+            }
+        }
+        removed
+    }
+
+    fn save_state<W: Write>(&mut self, path: &str, output: &mut W) -> io::Result<bool> {
+        let state = SaveState::capture(&self.executor);
+        if let Err(error) = fs::write(path, state.to_bytes()) {
+            writeln!(output, "{}", error)?;
+        }
+        Ok(true)
+    }
+
+    fn load_state<W: Write>(&mut self, path: &str, output: &mut W) -> io::Result<bool> {
+        let result = match fs::read(path) {
+            Ok(data) => SaveState::from_bytes(&data)
+                .and_then(|state| state.restore(&mut self.executor))
+                .map_err(|error| error.to_string()),
+            Err(error) => Err(error.to_string()),
+        };
+
+        match result {
+            Ok(()) => self.show_registers(output)?,
+            Err(error) => writeln!(output, "{}", error)?,
+        }
+        Ok(true)
+    }
+
+    fn examine<W: Write>(&mut self, args: &[&str], output: &mut W) -> io::Result<bool> {
+        match args {
+            [] => {
+                self.show_registers(output)?;
+                Ok(true)
+            }
+            [register, value] => {
+                let register = match register.parse::<RegisterName>() {
+                    Ok(register) => register,
+                    Err(_) => return Ok(false),
+                };
+                match parse_number(value) {
+                    Some(value) if !register.is_byte() || value <= 0xFF => {
+                        register.set(self.executor.cpu_mut(), value);
+                        self.executor.clear_history();
+                        Ok(true)
+                    }
+                    _ => Ok(false),
+                }
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn dump<W: Write>(&mut self, args: &[&str], output: &mut W) -> io::Result<bool> {
+        let numbers = match parse_numbers(args) {
+            Some(numbers) => numbers,
+            None => return Ok(false),
+        };
+        let (start, end) = match numbers[..] {
+            [] => (
+                self.next_dump,
+                self.next_dump.saturating_add(DUMP_LENGTH - 1),
+            ),
+            [start] => (start, start.saturating_add(DUMP_LENGTH - 1)),
+            [start, end] if start <= end => (start, end),
+            _ => return Ok(false),
+        };
+
+        let bus = &self.executor.cpu().bus;
+        let mut address = start as u32;
+        while address <= end as u32 {
+            let line_end = (address | (DUMP_LINE as u32 - 1)).min(end as u32);
+            let bytes: Vec<u8> = (address..=line_end)
+                .map(|a| bus.peek_byte(a as u16))
+                .collect();
+
+            // Lines are aligned to 16 bytes so that columns line up across dumps
+            let padding = (address % DUMP_LINE as u32) as usize;
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let text: String = bytes
+                .iter()
+                .map(|&b| {
+                    if (0x20..0x7F).contains(&b) {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            writeln!(
+                output,
+                "{:04X}  {}{:<w$}  {}{}",
+                address,
+                "   ".repeat(padding),
+                hex.join(" "),
+                " ".repeat(padding),
+                text,
+                w = (DUMP_LINE as usize - padding) * 3 - 1
+            )?;
+            address = line_end + 1;
+        }
+        self.next_dump = (end as u32 + 1) as u16;
+        Ok(true)
+    }
+
+    fn substitute(&mut self, args: &[&str]) -> bool {
+        let numbers = match parse_numbers(args) {
+            Some(numbers) if numbers.len() >= 2 => numbers,
+            _ => return false,
+        };
+        if numbers[1..].iter().any(|value| *value > 0xFF) {
+            return false;
+        }
+
+        let bytes: Vec<u8> = numbers[1..].iter().map(|value| *value as u8).collect();
+        if self
+            .executor
+            .cpu_mut()
+            .bus
+            .load_bytes(numbers[0], &bytes)
+            .is_err()
+        {
+            return false;
+        }
+        self.executor.clear_history();
+        true
+    }
+
+    fn list<W: Write>(&mut self, args: &[&str], output: &mut W) -> io::Result<bool> {
+        let numbers = match parse_numbers(args) {
+            Some(numbers) => numbers,
+            None => return Ok(false),
+        };
+        let cpu = self.executor.cpu();
+        let (start, count) = match numbers[..] {
+            [] => (listing_start(&cpu.bus, cpu.pc), LIST_LENGTH),
+            [start] => (start, LIST_LENGTH),
+            [start, count] => (start, count as usize),
+            _ => return Ok(false),
+        };
+
+        let mut address = start;
+        for _ in 0..count {
+            let line = self.disassembler.line_at(&cpu.bus, address);
+            let marker = if address == cpu.pc { '>' } else { ' ' };
+            writeln!(output, "{}{}", marker, line)?;
+            address = address.wrapping_add(line.bytes.len() as u16);
+        }
+        Ok(true)
+    }
+
+    /// Prints the registers in DDT's layout, followed by INTE, HLT, the cycle counter
+    /// and the next instruction
+    fn show_registers<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        let cpu = self.executor.cpu();
+        let flag = |bit: usize| (cpu.flags >> bit) & 1;
+        let (instruction, _) = decode_at(&cpu.bus, cpu.pc);
+
+        writeln!(
+            output,
+            "C{}Z{}M{}E{}I{} A={:02X} B={:04X} D={:04X} H={:04X} S={:04X} P={:04X} IE={} HLT={} CYC={}  {}",
+            flag(CARRY_FLAG),
+            flag(ZERO_FLAG),
+            flag(SIGN_FLAG),
+            flag(PARITY_FLAG),
+            flag(AUX_CARRY_FLAG),
+            cpu.a,
+            cpu.get_bc(),
+            cpu.get_de(),
+            cpu.get_hl(),
+            cpu.sp,
+            cpu.pc,
+            cpu.inte as u8,
+            cpu.halted as u8,
+            self.executor.get_cycles(),
+            instruction
+        )
+    }
+}
+
+/// Parses a hexadecimal number, optionally followed by `h`
+fn parse_number(text: &str) -> Option<u16> {
+    let digits = text
+        .strip_suffix('h')
+        .or_else(|| text.strip_suffix('H'))
+        .unwrap_or(text);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+fn parse_numbers(args: &[&str]) -> Option<Vec<u16>> {
+    args.iter().map(|arg| parse_number(arg)).collect()
+}
+
+fn access_name(access: Access) -> &'static str {
+    match access {
+        Access::Read => "R",
+        Access::Write => "W",
+        Access::ReadWrite => "RW",
+    }
+}
+
+/// Looks for an address shortly before `pc` from which decoding lands exactly on `pc`,
+/// so that a listing shows the instructions leading up to it
+fn listing_start(bus: &Bus, pc: u16) -> u16 {
+    for distance in (1..=LIST_LOOKBEHIND).rev() {
+        let start = pc.wrapping_sub(distance);
+        let mut offset = 0;
+        while offset < distance {
+            offset += decode_at(bus, start.wrapping_add(offset)).1 as u16;
+        }
+        if offset == distance {
+            return start;
+        }
+    }
+    pc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `MVI A,11h` and `STA 0040h`, then applies `edit` and tries to take back one instruction
+    fn reverse_after_edit(edit: &str) -> (CPU, String) {
+        let mut cpu = CPU::new();
+        cpu.bus
+            .load_bytes(0, &[0x3E, 0x11, 0x32, 0x40, 0x00])
+            .unwrap();
+        let mut output = Vec::new();
+        {
+            let mut monitor = Monitor::new(Executor::new(&mut cpu));
+            for line in &["T 2", edit] {
+                assert!(monitor.command(line, &mut io::sink()).unwrap());
+            }
+            monitor.command("R", &mut output).unwrap();
+        }
+        (cpu, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn substitute_clears_history() {
+        let (cpu, output) = reverse_after_edit("S 40 22");
+        assert!(output.starts_with("start of history\n"));
+        assert_eq!(cpu.bus.peek_byte(0x0040), 0x22);
+        assert_eq!(cpu.pc, 0x0005);
+    }
+
+    #[test]
+    fn examine_clears_history() {
+        let (cpu, output) = reverse_after_edit("X A 33");
+        assert!(output.starts_with("start of history\n"));
+        assert_eq!(cpu.a, 0x33);
+        assert_eq!(cpu.bus.peek_byte(0x0040), 0x11);
+    }
+
+    #[test]
+    fn reverse_trace_without_edit_undoes_the_store() {
+        let (cpu, output) = reverse_after_edit("D 40 40");
+        assert!(!output.starts_with("start of history"));
+        assert_eq!(cpu.bus.peek_byte(0x0040), 0x00);
+        assert_eq!(cpu.pc, 0x0002);
+    }
+
+    /// Counts A down from 3, stores it to 0040h and halts
+    const COUNTDOWN: [u8; 10] = [
+        0x3E, 0x03, // MVI A,03h
+        0x3D, // DCR A
+        0xC2, 0x02, 0x00, // JNZ 0002h
+        0x32, 0x40, 0x00, // STA 0040h
+        0x76, // HLT
+    ];
+
+    /// Feeds `input` to a monitor for `program` and returns the machine and the output
+    fn session(program: &[u8], input: &str) -> (CPU, String) {
+        let mut cpu = CPU::new();
+        cpu.bus.load_bytes(0, program).unwrap();
+        let mut output = Vec::new();
+        Monitor::new(Executor::new(&mut cpu))
+            .run(input.as_bytes(), &mut output)
+            .unwrap();
+        (cpu, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn trace_shows_the_registers_after_each_instruction() {
+        let (cpu, output) = session(&COUNTDOWN, "T 2\nQ\n");
+        assert_eq!(
+            output,
+            "C0Z0M0E0I0 A=00 B=0000 D=0000 H=0000 S=0000 P=0000 IE=0 HLT=0 CYC=0  MVI A,03h\n\
+             -C0Z0M0E0I0 A=03 B=0000 D=0000 H=0000 S=0000 P=0002 IE=0 HLT=0 CYC=7  DCR A\n\
+             C0Z0M0E0I1 A=02 B=0000 D=0000 H=0000 S=0000 P=0003 IE=0 HLT=0 CYC=12  JNZ 0002h\n\
+             -"
+        );
+        assert_eq!(cpu.pc, 0x0003);
+    }
+
+    #[test]
+    fn go_runs_until_hlt() {
+        let (cpu, output) = session(&COUNTDOWN, "G\nQ\n");
+        assert!(output.ends_with(
+            "-halted\n\
+             C0Z1M0E1I1 A=00 B=0000 D=0000 H=0000 S=0000 P=000A IE=0 HLT=1 CYC=72  NOP\n-"
+        ));
+        assert!(cpu.halted);
+
+        let (cpu, _) = session(&COUNTDOWN, "X A 7\nG 6\nQ\n");
+        assert_eq!(cpu.bus.peek_byte(0x0040), 0x07);
+    }
+
+    #[test]
+    fn go_hands_control_back_from_an_endless_loop() {
+        let mut cpu = CPU::new();
+        cpu.bus.load_bytes(0, &[0xC3, 0x00, 0x00]).unwrap();
+        let mut output = Vec::new();
+        let mut monitor = Monitor::new(Executor::new(&mut cpu));
+        monitor.go_limit = 1000;
+        monitor.command("G", &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(
+            output.starts_with("still running after 1000 instructions\n"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn breakpoints_stop_go() {
+        let (cpu, output) = session(&COUNTDOWN, "B 6\nB\nG\nQ\n");
+        assert!(output.contains("-0006\n-*0006\n"), "{}", output);
+        assert_eq!(cpu.pc, 0x0006);
+
+        let (cpu, output) = session(&COUNTDOWN, "B 2 A == 1\nB\nG\nQ\n");
+        assert!(output.contains("-0002 if A == 01h\n-*0002\n"), "{}", output);
+        assert_eq!((cpu.pc, cpu.a), (0x0002, 0x01));
+    }
+
+    #[test]
+    fn dump_shows_substituted_bytes() {
+        let (cpu, output) = session(&COUNTDOWN, "S 40 41 42\nD 40 41\nS 40 100\nQ\n");
+        assert!(
+            output.contains(&format!("-0040  {:<47}  AB\n-?\n", "41 42")),
+            "{}",
+            output
+        );
+        assert_eq!(cpu.bus.peek_byte(0x0041), 0x42);
+    }
+
+    #[test]
+    fn list_marks_the_pc() {
+        let (_, output) = session(&COUNTDOWN, "T\nL 0 3\nQ\n");
+        assert!(output.ends_with(
+            "- 0000  3E 03     MVI A,03h\n\
+             >0002  3D        DCR A\n \
+             0003  C2 02 00  JNZ 0002h\n-"
+        ));
+    }
+
+    #[test]
+    fn examine_sets_registers() {
+        let (cpu, output) = session(&COUNTDOWN, "X A 12\nX\nX A 100\nX PC 6\nQ\nX B 1\n");
+        assert!(output.contains(
+            "-C0Z0M0E0I0 A=12 B=0000 D=0000 H=0000 S=0000 P=0000 IE=0 HLT=0 CYC=0  MVI A,03h\n-?\n"
+        ));
+        assert_eq!((cpu.a, cpu.pc, cpu.b), (0x12, 0x0006, 0x00));
+    }
+}
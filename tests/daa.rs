@@ -0,0 +1,40 @@
+use i8080_emu::i8080::*;
+
+const CY: u8 = 0x01;
+const AC: u8 = 0x10;
+const DAA: u8 = 0x27;
+
+/// Runs DAA for all 256 accumulator values with all four CY/AC combinations
+/// and compares against the reference table in `fixtures/daa.txt`
+#[test]
+fn daa_matches_reference_table() {
+    let table = include_str!("fixtures/daa.txt");
+    let mut checked = 0;
+
+    for line in table.lines().filter(|line| !line.starts_with('#')) {
+        let fields: Vec<u8> = line
+            .split_whitespace()
+            .map(|field| u8::from_str_radix(field, 16).unwrap())
+            .collect();
+        let (a, carry, aux, result, flags) =
+            (fields[0], fields[1], fields[2], fields[3], fields[4]);
+
+        let mut cpu = CPU::new();
+        cpu.bus.load_bytes(0, &[DAA]).unwrap();
+        cpu.a = a;
+        cpu.set_flags(if carry == 1 { CY } else { 0 } | if aux == 1 { AC } else { 0 });
+        Executor::new(&mut cpu).execute().unwrap();
+
+        assert_eq!(
+            (cpu.a, cpu.flags),
+            (result, flags),
+            "DAA with A={:02X} CY={} AC={}",
+            a,
+            carry,
+            aux
+        );
+        checked += 1;
+    }
+
+    assert_eq!(checked, 256 * 4);
+}
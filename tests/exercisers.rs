@@ -0,0 +1,233 @@
+//! Runs the CPU exercisers in `fixtures/exercisers` as CP/M programs.
+//!
+//! The slowest groups of the 8080 instruction exerciser take minutes and are ignored,
+//! run them with `cargo test --release --test exercisers -- --ignored`. CI runs every
+//! group in release, in the `exerciser` job of `.github/workflows/ci.yml`.
+
+use i8080_emu::i8080::*;
+
+/// Operand of the `LXI H` that points the 8080 exerciser at its table of groups
+const GROUP_TABLE_POINTER: u16 = 0x0120;
+/// `crcval` of the 8080 exerciser, the CRC of the group that ran last, most significant byte first
+const GROUP_CRC: u16 = 0x0EB3;
+
+/// Groups of the 8080 exerciser with the CRCs of a real 8080, as published with 8080EXM
+const GROUPS: [(&str, u32); 25] = [
+    ("dad <b,d,h,sp>", 0x1447_4ba6),
+    ("aluop nn", 0x9e92_2f9e),
+    ("aluop <b,c,d,e,h,l,m,a>", 0xcf76_2c86),
+    ("<daa,cma,stc,cmc>", 0xbb3f_030c),
+    ("<inr,dcr> a", 0xadb6_460e),
+    ("<inr,dcr> b", 0x83ed_1345),
+    ("<inx,dcx> b", 0xf792_87cd),
+    ("<inr,dcr> c", 0xe5f6_721b),
+    ("<inr,dcr> d", 0x15b5_579a),
+    ("<inx,dcx> d", 0x7f4e_2501),
+    ("<inr,dcr> e", 0xcf2a_b396),
+    ("<inr,dcr> h", 0x12b2_952c),
+    ("<inx,dcx> h", 0x9f2b_23c0),
+    ("<inr,dcr> l", 0xff57_d356),
+    ("<inr,dcr> m", 0x92e9_63bd),
+    ("<inx,dcx> sp", 0xd570_2fab),
+    ("lhld nnnn", 0xa9c3_d5cb),
+    ("shld nnnn", 0xe886_4f26),
+    ("lxi <b,d,h,sp>,nnnn", 0xfcf4_6e12),
+    ("ldax <b,d>", 0x2b82_1d5f),
+    ("mvi <b,c,d,e,h,l,m,a>,nn", 0xeaa7_2044),
+    ("mov <bcdehla>,<bcdehla>", 0x10b5_8cee),
+    ("sta nnnn / lda nnnn", 0xed57_af72),
+    ("<rlc,rrc,ral,rar>", 0xe0d8_9235),
+    ("stax <b,d>", 0x2b04_71e9),
+];
+
+fn peek_word(cpu: &CPU, address: u16) -> u16 {
+    u16::from_le_bytes([cpu.bus.peek_byte(address), cpu.bus.peek_byte(address + 1)])
+}
+
+/// Runs a .COM program after `patch` had a go at the loaded machine and returns the console output
+fn run(program: &[u8], cpu: &mut CPU, patch: impl FnOnce(&mut CPU)) -> String {
+    load_com(cpu, program).unwrap();
+    patch(cpu);
+
+    let mut executor = Executor::new(cpu);
+    let mut console = Vec::new();
+    let exit = run_com(&mut executor, &mut console).unwrap();
+    let console = String::from_utf8(console).unwrap();
+    assert_eq!(exit, CpmExit::WarmBoot, "console output: {:?}", console);
+    console
+}
+
+#[test]
+fn tst8080() {
+    let program = include_bytes!("fixtures/exercisers/TST8080.COM");
+    let console = run(program, &mut CPU::new(), |_| {});
+    assert_eq!(console, "\u{c}\r\n CPU IS OPERATIONAL");
+}
+
+#[test]
+fn cputest() {
+    let program = include_bytes!("fixtures/exercisers/CPUTEST.COM");
+    let console = run(program, &mut CPU::new(), |_| {});
+    assert!(console.contains("CPU IS 8080/8085"), "{:?}", console);
+    assert!(console.ends_with("\r\nCPU TESTS OK\r\n"), "{:?}", console);
+}
+
+/// Runs a single group of the 8080 exerciser by pointing it at the group's table
+/// entry and ending the table after it, then checks the CRC it computed
+fn exercise(group: usize) {
+    let program = include_bytes!("fixtures/exercisers/8080EX1.COM");
+    let (name, crc) = GROUPS[group];
+
+    let mut cpu = CPU::new();
+    let console = run(program, &mut cpu, |cpu| {
+        let entry = peek_word(cpu, GROUP_TABLE_POINTER) + 2 * group as u16;
+        cpu.bus
+            .load_bytes(GROUP_TABLE_POINTER, &entry.to_le_bytes())
+            .unwrap();
+        cpu.bus.load_bytes(entry + 2, &[0, 0]).unwrap();
+    });
+
+    assert_eq!(
+        console,
+        format!(
+            "8080 instruction exerciser (KR580VM80A CPU)\n\r{:.<30}  OK\n\rTests complete",
+            name
+        )
+    );
+    let found = u32::from_be_bytes([
+        cpu.bus.peek_byte(GROUP_CRC),
+        cpu.bus.peek_byte(GROUP_CRC + 1),
+        cpu.bus.peek_byte(GROUP_CRC + 2),
+        cpu.bus.peek_byte(GROUP_CRC + 3),
+    ]);
+    assert_eq!(found, crc, "CRC of {}", name);
+}
+
+#[test]
+#[ignore = "slow, about a billion cycles"]
+fn exerciser_dad() {
+    exercise(0);
+}
+
+#[test]
+#[ignore = "slow, about half a billion cycles"]
+fn exerciser_aluop_immediate() {
+    exercise(1);
+}
+
+#[test]
+#[ignore = "slow, about twenty billion cycles"]
+fn exerciser_aluop_register() {
+    exercise(2);
+}
+
+#[test]
+#[ignore = "slow, about a billion cycles"]
+fn exerciser_daa_cma_stc_cmc() {
+    exercise(3);
+}
+
+#[test]
+fn exerciser_inr_dcr_a() {
+    exercise(4);
+}
+
+#[test]
+fn exerciser_inr_dcr_b() {
+    exercise(5);
+}
+
+#[test]
+fn exerciser_inx_dcx_b() {
+    exercise(6);
+}
+
+#[test]
+fn exerciser_inr_dcr_c() {
+    exercise(7);
+}
+
+#[test]
+fn exerciser_inr_dcr_d() {
+    exercise(8);
+}
+
+#[test]
+fn exerciser_inx_dcx_d() {
+    exercise(9);
+}
+
+#[test]
+fn exerciser_inr_dcr_e() {
+    exercise(10);
+}
+
+#[test]
+fn exerciser_inr_dcr_h() {
+    exercise(11);
+}
+
+#[test]
+fn exerciser_inx_dcx_h() {
+    exercise(12);
+}
+
+#[test]
+fn exerciser_inr_dcr_l() {
+    exercise(13);
+}
+
+#[test]
+fn exerciser_inr_dcr_m() {
+    exercise(14);
+}
+
+#[test]
+fn exerciser_inx_dcx_sp() {
+    exercise(15);
+}
+
+#[test]
+fn exerciser_lhld() {
+    exercise(16);
+}
+
+#[test]
+fn exerciser_shld() {
+    exercise(17);
+}
+
+#[test]
+fn exerciser_lxi() {
+    exercise(18);
+}
+
+#[test]
+fn exerciser_ldax() {
+    exercise(19);
+}
+
+#[test]
+fn exerciser_mvi() {
+    exercise(20);
+}
+
+#[test]
+fn exerciser_mov() {
+    exercise(21);
+}
+
+#[test]
+fn exerciser_sta_lda() {
+    exercise(22);
+}
+
+#[test]
+fn exerciser_rotate() {
+    exercise(23);
+}
+
+#[test]
+fn exerciser_stax() {
+    exercise(24);
+}
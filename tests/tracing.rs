@@ -0,0 +1,29 @@
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// With the default `NullTracer` a run prints the monitor's output and nothing else
+#[test]
+fn default_run_prints_no_trace_events() {
+    let path = std::env::temp_dir().join(format!("i8080_emu_trace_{}.bin", std::process::id()));
+    // MVI A,42h; OUT 10h; STA 0040h; HLT
+    fs::write(&path, [0x3E, 0x42, 0xD3, 0x10, 0x32, 0x40, 0x00, 0x76]).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_i8080_emu"))
+        .arg("debug")
+        .arg(&path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"G\nQ\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    fs::remove_file(&path).unwrap();
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(output.status.success());
+    assert!(stdout.contains("halted"), "{}", stdout);
+    for tag in &["[BUS]", "[IO]", "[DECODER]", "[EXECUTOR]"] {
+        assert!(!stdout.contains(tag), "{}", stdout);
+    }
+}